@@ -4,14 +4,26 @@ use std::{
     sync::mpsc::{channel, Receiver, Sender, TryRecvError},
 };
 
+/// The kind of dialog a picker shows
+#[derive(Clone, Copy)]
+pub enum PickKind {
+    Folder,
+    OpenProject,
+    SaveProject,
+}
+
 pub struct FolderPickWorker {
     receiver: Receiver<Option<PathBuf>>,
 }
 
 impl FolderPickWorker {
     pub fn start() -> Self {
+        Self::start_kind(PickKind::Folder)
+    }
+
+    pub fn start_kind(kind: PickKind) -> Self {
         let (tx, rx) = channel::<Option<PathBuf>>();
-        std::thread::spawn(move || pick_folder(tx));
+        std::thread::spawn(move || pick_folder(tx, kind));
         Self { receiver: rx }
     }
 
@@ -28,8 +40,17 @@ impl FolderPickWorker {
     }
 }
 
-fn pick_folder(sender: Sender<Option<PathBuf>>) {
-    let result = FileDialog::new().pick_folder();
+fn pick_folder(sender: Sender<Option<PathBuf>>, kind: PickKind) {
+    let result = match kind {
+        PickKind::Folder => FileDialog::new().pick_folder(),
+        PickKind::OpenProject => FileDialog::new()
+            .add_filter("Project", &["toml"])
+            .pick_file(),
+        PickKind::SaveProject => FileDialog::new()
+            .add_filter("Project", &["toml"])
+            .set_file_name("project.toml")
+            .save_file(),
+    };
     if let Err(why) = sender.send(result) {
         eprintln!("Couldn't send pick_folder result: {why}");
     }
@@ -1,7 +1,9 @@
 // Don't open a command prompt on Windows
 #![windows_subsystem = "windows"]
 
-use aeco_patch_config::{error::PatchConfigError, generate_config};
+use aeco_patch_config::{
+    error::PatchConfigError, generate_config, options::GenerateOptions, project::ProjectConfig,
+};
 use eframe::egui;
 use eframe::epaint::Vec2;
 use std::path::{Path, PathBuf};
@@ -11,12 +13,17 @@ use std::{sync::mpsc, thread};
 mod file_tree;
 use file_tree::FileTree;
 mod folder_picker;
-use folder_picker::FolderPickWorker;
+use folder_picker::{FolderPickWorker, PickKind};
+
+/// Name of the folder created inside the output folder when no project says
+/// otherwise
+const DEFAULT_OUTPUT_NAME: &str = "aeco-patch";
 
 /// Messages which the worker thread (for generating configs) can send back to
 /// the GUI about the result of the operation.
 enum MessageToGUI {
-    Complete,
+    /// The output was generated, possibly with warnings
+    Complete(Vec<String>),
     Error(PatchConfigError),
 }
 
@@ -29,6 +36,14 @@ struct PatchConfigApp {
     worker_rx: Option<Receiver<MessageToGUI>>,
     file_tree: Option<FileTree>,
     maintenance_mode: bool,
+    /// Options from the last opened project, which the GUI has no controls for
+    options: GenerateOptions,
+    /// Name of the folder which is generated inside the output folder
+    output_name: String,
+    /// Release channel from the last opened project, if it generates one
+    release_channel: Option<String>,
+    open_project_picker: Option<FolderPickWorker>,
+    save_project_picker: Option<FolderPickWorker>,
 }
 
 impl PatchConfigApp {
@@ -42,6 +57,11 @@ impl PatchConfigApp {
             worker_rx: None,
             file_tree: None,
             maintenance_mode: false,
+            options: GenerateOptions::default(),
+            output_name: DEFAULT_OUTPUT_NAME.to_string(),
+            release_channel: None,
+            open_project_picker: None,
+            save_project_picker: None,
         }
     }
 
@@ -65,16 +85,28 @@ impl PatchConfigApp {
         let input_dir = input_dir.to_path_buf();
         let output_dir = output_dir.to_path_buf();
 
-        let maintenance_mode = self.maintenance_mode;
+        let options = GenerateOptions {
+            maintenance: self.maintenance_mode,
+            ..self.options.clone()
+        };
+        let release_channel = self.release_channel.clone();
 
         // Generate the configuration on a new thread
         thread::spawn(move || {
-            let result = generate_config(input_dir, output_dir, maintenance_mode);
+            let result = match release_channel {
+                Some(release_channel) => aeco_patch_config::channel::generate_channel(
+                    input_dir,
+                    output_dir,
+                    &release_channel,
+                    &options,
+                ),
+                None => generate_config(input_dir, output_dir, &options),
+            };
 
             // Send a response to the GUI depending on what the result of the
             // operation was
             let message = match result {
-                Ok(_) => MessageToGUI::Complete,
+                Ok(report) => MessageToGUI::Complete(report.warnings),
                 Err(why) => MessageToGUI::Error(why),
             };
 
@@ -103,8 +135,15 @@ impl PatchConfigApp {
             // Provide feedback to the user depending on the result of the
             // operation
             match message {
-                MessageToGUI::Complete => {
-                    self.set_message("Finished!");
+                MessageToGUI::Complete(warnings) => {
+                    if warnings.is_empty() {
+                        self.set_message("Finished!");
+                    } else {
+                        self.set_message(&format!(
+                            "Finished with warnings:\n{}",
+                            warnings.join("\n")
+                        ));
+                    }
                 }
                 MessageToGUI::Error(why) => {
                     self.set_message(&format!("Failled to generate output: {}", why.to_string()));
@@ -129,7 +168,7 @@ impl PatchConfigApp {
             if self.worker_rx.is_none() {
                 let mut output_dir = PathBuf::new();
                 output_dir.push(&self.patch_output_folder);
-                output_dir.push("aeco-patch");
+                output_dir.push(&self.output_name);
 
                 let input_dir = PathBuf::from(&self.patch_folder);
                 self.start_config_worker(&input_dir, &output_dir);
@@ -161,6 +200,105 @@ impl PatchConfigApp {
         }
     }
 
+    /// Starts a file picker for a project to open on a new thread
+    fn open_project_button(&mut self, ui: &mut egui::Ui) {
+        if !ui.button("Open Project").clicked() {
+            return;
+        }
+
+        if self.open_project_picker.is_none() {
+            self.open_project_picker = Some(FolderPickWorker::start_kind(PickKind::OpenProject));
+        }
+    }
+
+    /// Starts a file picker for where to save the project on a new thread
+    fn save_project_button(&mut self, ui: &mut egui::Ui) {
+        if !ui.button("Save Project").clicked() {
+            return;
+        }
+
+        if self.save_project_picker.is_none() {
+            self.save_project_picker = Some(FolderPickWorker::start_kind(PickKind::SaveProject));
+        }
+    }
+
+    /// Describes everything shown in the GUI as a project
+    fn project(&self) -> ProjectConfig {
+        ProjectConfig {
+            source: PathBuf::from(&self.patch_folder),
+            output: PathBuf::from(&self.patch_output_folder).join(&self.output_name),
+            channel: self.release_channel.clone(),
+            options: GenerateOptions {
+                maintenance: self.maintenance_mode,
+                ..self.options.clone()
+            },
+        }
+    }
+
+    /// Fills the GUI in from a project
+    fn load_project(&mut self, project: ProjectConfig) {
+        self.patch_folder = project.source.to_string_lossy().to_string();
+
+        match (project.output.parent(), project.output.file_name()) {
+            (Some(parent), Some(name)) => {
+                self.patch_output_folder = parent.to_string_lossy().to_string();
+                self.output_name = name.to_string_lossy().to_string();
+            }
+            _ => {
+                self.patch_output_folder = project.output.to_string_lossy().to_string();
+                self.output_name = DEFAULT_OUTPUT_NAME.to_string();
+            }
+        }
+
+        self.maintenance_mode = project.options.maintenance;
+        self.release_channel = project.channel;
+        self.options = project.options;
+
+        self.file_tree = FileTree::new(&project.source).ok();
+    }
+
+    /// Checks to see if the project pickers have responded, and opens or
+    /// saves the project if they have.
+    fn update_project(&mut self) {
+        let mut open_path: Option<PathBuf> = None;
+        let mut save_path: Option<PathBuf> = None;
+
+        if let Some(worker) = &self.open_project_picker {
+            if let Some(optional_path) = &worker.result() {
+                open_path = optional_path.clone();
+                self.open_project_picker = None;
+            }
+        }
+
+        if let Some(worker) = &self.save_project_picker {
+            if let Some(optional_path) = &worker.result() {
+                save_path = optional_path.clone();
+                self.save_project_picker = None;
+            }
+        }
+
+        if let Some(path) = open_path {
+            match ProjectConfig::load(&path) {
+                Ok(project) => {
+                    self.load_project(project);
+                    self.set_message("Opened project.");
+                }
+                Err(why) => {
+                    self.set_message(&format!("Failed to open project: {}", why.to_string()));
+                }
+            }
+        }
+
+        if let Some(path) = save_path {
+            match self.project().save(&path) {
+                Ok(_) => self.set_message("Saved project."),
+                Err(why) => {
+                    self.set_message(&format!("Failed to save project: {}", why.to_string()));
+                }
+            }
+        }
+    }
+
     /// Checks to see if there are responses from any file picker workers.
     /// Updates the paths in the GUI if there are responses.
     fn update_folders(&mut self) {
@@ -219,9 +357,16 @@ impl eframe::App for PatchConfigApp {
     fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
         ctx.request_repaint_after(Duration::from_millis(10));
         self.update_folders();
+        self.update_project();
         self.check_config_worker();
 
         egui::CentralPanel::default().show(ctx, |ui| {
+            egui::TopBottomPanel::top("project-panel").show_inside(ui, |ui| {
+                ui.horizontal(|ui| {
+                    self.open_project_button(ui);
+                    self.save_project_button(ui);
+                });
+            });
             egui::TopBottomPanel::top("top-panel").show_inside(ui, |ui| {
                 egui::SidePanel::right("generate-panel")
                     .frame(egui::Frame::none())
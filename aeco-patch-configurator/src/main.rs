@@ -1,25 +1,759 @@
-use clap::Parser;
+use aeco_patch_config::{
+    channel,
+    client::{ArchivePacker, ClientOptions, CommandPacker, PatchClient, PatchSource},
+    compression::Codec,
+    error::PatchConfigError,
+    estimate,
+    options::{
+        ArchiveConflictPolicy, ArchiveExtensions, BudgetOptions, GenerateOptions, LauncherOptions,
+        LinkMode, OutputLayout, ParallelismOptions,
+    },
+    project::ProjectConfig,
+    report::GenerationReport,
+    signing,
+    status::ServerStatus,
+};
+use clap::{Args, CommandFactory, ErrorKind, Parser, Subcommand};
+use serve::ServeOptions;
+use std::{path::PathBuf, time::Duration};
+
+mod serve;
 
 /// Generate configuration for an AECO patch server
 #[derive(Parser, Debug)]
-struct Args {
-    /// Path to ECO folder
-    eco_dir: String,
+#[clap(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    generate: GenerateArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate configuration as described by a project file
+    Build {
+        /// Path to the project's TOML file
+        #[clap(long)]
+        config: String,
+
+        #[clap(flatten)]
+        report: ReportArgs,
+    },
+
+    /// Make the release of one channel the release of another, such as
+    /// staging to live
+    Promote {
+        /// Output which holds the channels
+        output_dir: String,
+
+        /// Channel whose release is promoted
+        from: String,
+
+        /// Channel which receives the release
+        to: String,
+
+        /// Secret key with which to sign the updated release history
+        #[clap(long)]
+        signing_key: Option<String>,
+    },
+
+    /// Create a key pair for signing metadata
+    Keygen {
+        /// Where to write the secret key, which is used when generating
+        secret_key: String,
+
+        /// Where to write the public key, which is given to launchers
+        public_key: String,
+    },
+
+    /// Serve an output over HTTP on this machine, for testing launchers
+    Serve {
+        /// Output to serve
+        output_dir: String,
+
+        /// Port to listen on
+        #[clap(long, default_value_t = 8080)]
+        port: u16,
+
+        /// Address to listen on. Use 0.0.0.0 to serve other machines.
+        #[clap(long, default_value = "127.0.0.1")]
+        bind: String,
+
+        /// Delay every response by this many milliseconds
+        #[clap(long, default_value_t = 0)]
+        latency_ms: u64,
+
+        /// Limit each response to this many bytes per second
+        #[clap(long)]
+        bandwidth: Option<u64>,
+
+        /// Fail this fraction of requests, from 0 to 1, with 503
+        #[clap(long, default_value_t = 0.0)]
+        failure_rate: f64,
+    },
+
+    /// Estimate how much players on earlier releases download to update to
+    /// the release in an output
+    Estimate {
+        /// Output holding the new release
+        output_dir: String,
+
+        /// Outputs holding the earlier releases
+        #[clap(required = true)]
+        previous_outputs: Vec<String>,
+
+        /// How many of the largest downloads to list for each release
+        #[clap(long, default_value_t = 10)]
+        top: usize,
+
+        /// Extension of unpacked archive folders the outputs were generated
+        /// with (default: archive)
+        #[clap(long)]
+        unpacked_extension: Option<String>,
+    },
+
+    /// Bring an install up to date with an output, as a launcher would
+    Update {
+        /// Output to update from, either a directory or an http:// URL
+        source: String,
+
+        /// Directory the game is installed in
+        install_dir: String,
+
+        /// Extension of unpacked archive folders the output was generated
+        /// with (default: archive)
+        #[clap(long)]
+        unpacked_extension: Option<String>,
+
+        /// Only print what would change
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Channel to follow, such as live, in an output with channels
+        #[clap(long)]
+        channel: Option<String>,
+
+        /// Public key which every metadata file must be signed with
+        #[clap(long)]
+        public_key: Option<String>,
+
+        /// Program which repacks unpacked archives into their HED and DAT,
+        /// run as PROGRAM ENTRIES_DIR HED DAT
+        #[clap(long)]
+        repack_command: Option<String>,
+    },
+
+    /// Check files against their detached .sig signatures
+    Verify {
+        /// Public key to check the signatures with
+        #[clap(long)]
+        public_key: String,
+
+        /// Files to check, such as meta/patchlist.json
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct GenerateArgs {
+    /// Path to ECO folder, or to a .zip, .tar or .tar.zst of the client
+    /// build, optionally followed by the ECO folder's path within it, such as
+    /// build.zip/ECO
+    eco_dir: Option<String>,
 
     /// Path in which to generate configuration files
-    output_dir: String,
+    output_dir: Option<String>,
 
     /// If the server should be in maintenance mode
     #[clap(short, long)]
     maintenance_mode: bool,
+
+    /// Extension of archive data files, may be repeated (default: dat)
+    #[clap(long = "data-extension")]
+    data_extensions: Vec<String>,
+
+    /// Extension of archive header files, may be repeated (default: hed)
+    #[clap(long = "metadata-extension")]
+    metadata_extensions: Vec<String>,
+
+    /// Extension of unpacked archive folders, may be repeated (default: archive)
+    #[clap(long = "unpacked-extension")]
+    unpacked_extensions: Vec<String>,
+
+    /// Let unpacked archive folders override entries of packed archives with
+    /// the same name, instead of failing
+    #[clap(long)]
+    merge_unpacked_archives: bool,
+
+    /// Glob of HEDs, relative to the ECO folder, whose archives should be
+    /// published packed instead of unpacked. May be repeated.
+    #[clap(long = "packed-archive")]
+    packed_archives: Vec<String>,
+
+    /// Store each distinct file once as objects/<digest> instead of mirroring
+    /// the ECO folder
+    #[clap(long)]
+    content_addressed: bool,
+
+    /// Hardlink unchanged files into the output instead of copying them. The
+    /// output must be on the same filesystem as the ECO folder.
+    #[clap(long, conflicts_with = "reflink")]
+    hardlink: bool,
+
+    /// Reflink unchanged files into the output where the filesystem supports
+    /// it, copying them otherwise
+    #[clap(long)]
+    reflink: bool,
+
+    /// Write a precompressed variant of each file with this codec. May be
+    /// repeated.
+    #[clap(long = "compress", possible_values = ["gzip", "zstd", "brotli"])]
+    codecs: Vec<String>,
+
+    /// Only publish the compressed variants of files which get compressed
+    #[clap(long, requires = "codecs")]
+    compressed_only: bool,
+
+    /// Extension of files which should not be compressed, may be repeated.
+    /// Replaces the default list of already-compressed formats.
+    #[clap(long = "no-compress-extension")]
+    no_compress_extensions: Vec<String>,
+
+    /// Secret key with which to sign the generated metadata
+    #[clap(long)]
+    signing_key: Option<String>,
+
+    /// Release id, either a semantic version or a build number (default: the
+    /// build number after the previous release)
+    #[clap(long)]
+    release: Option<String>,
+
+    /// Output of the previous release, whose release history is carried
+    /// forward. Without it the history starts over, which is warned about
+    /// when an earlier output sits next to the target
+    #[clap(long)]
+    previous_output: Option<String>,
+
+    /// File in which to keep digests of source files between runs, so that
+    /// unchanged files aren't hashed again
+    #[clap(long)]
+    hash_cache: Option<String>,
+
+    /// Characters of the Windows path limit to leave for the folder players
+    /// install the game in [default: 100]
+    #[clap(long)]
+    windows_install_prefix: Option<usize>,
+
+    /// Folder laid over the ECO folder, overriding the files and archive
+    /// entries it shares paths with. May be repeated; later layers win.
+    #[clap(long = "layer")]
+    layers: Vec<String>,
+
+    /// Glob, relative to the install folder, of user-owned files which
+    /// launchers must never delete, such as screenshots. A folder protects
+    /// everything in it. May be repeated.
+    #[clap(long = "protect")]
+    protected: Vec<String>,
+
+    /// Put files matching a glob, relative to the ECO folder, in an optional
+    /// content pack, given as NAME=GLOB. May be repeated.
+    #[clap(long = "pack")]
+    packs: Vec<String>,
+
+    /// Glob, relative to the patch root, of files which may be downloaded in
+    /// the background while the game runs. May be repeated.
+    #[clap(long = "background")]
+    background: Vec<String>,
+
+    /// Glob, relative to the patch root, of executables and libraries which
+    /// require a restart when updated, in addition to **/*.exe and **/*.dll.
+    /// May be repeated.
+    #[clap(long = "executable")]
+    executable: Vec<String>,
+
+    /// Don't record the modification times of source files
+    #[clap(long)]
+    no_mtime: bool,
+
+    /// Launcher binary to publish for self-updates, outside the patch tree
+    #[clap(long, requires = "launcher-version")]
+    launcher: Option<String>,
+
+    /// Version of the launcher binary
+    #[clap(long, requires = "launcher")]
+    launcher_version: Option<String>,
+
+    /// Fail if players on the previous release would download more than this
+    /// many bytes to update
+    #[clap(long)]
+    max_delta_bytes: Option<u64>,
+
+    /// Fail if any file is larger than this many bytes
+    #[clap(long)]
+    max_file_bytes: Option<u64>,
+
+    /// Fail if the patch is larger than this many bytes in total
+    #[clap(long)]
+    max_total_bytes: Option<u64>,
+
+    /// Only warn when a size limit is exceeded
+    #[clap(long)]
+    soft_budget: bool,
+
+    /// Number of worker threads. Defaults to one per core.
+    #[clap(long, conflicts_with = "sequential")]
+    threads: Option<usize>,
+
+    /// Process one file at a time on a single thread
+    #[clap(long)]
+    sequential: bool,
+
+    /// Most source files to read at once, which helps on spinning disks and
+    /// network shares
+    #[clap(long)]
+    max_concurrent_reads: Option<usize>,
+
+    /// Most output files to write at once
+    #[clap(long)]
+    max_concurrent_writes: Option<usize>,
+
+    /// Start the largest files and archives of each directory first, after
+    /// its subdirectories
+    #[clap(long)]
+    largest_first: bool,
+
+    /// Generate a release channel, such as live or test, of an output which
+    /// holds several channels sharing storage. Implies --content-addressed.
+    #[clap(long)]
+    channel: Option<String>,
+
+    /// Glob of files or folders, relative to the ECO folder, to leave out of
+    /// the patch. May be repeated.
+    #[clap(long = "ignore")]
+    ignore: Vec<String>,
+
+    #[clap(flatten)]
+    report: ReportArgs,
+}
+
+/// Where to write the generation report, besides printing a summary
+#[derive(Args, Debug)]
+struct ReportArgs {
+    /// Write the report as JSON to this path
+    #[clap(long)]
+    report_json: Option<String>,
+
+    /// Write the report as Markdown to this path, such as for release notes
+    #[clap(long)]
+    report_markdown: Option<String>,
+
+    /// Write the report as an HTML page to this path
+    #[clap(long)]
+    report_html: Option<String>,
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Some(Command::Build { config, report }) => build(&config, &report),
+        Some(Command::Promote {
+            output_dir,
+            from,
+            to,
+            signing_key,
+        }) => promote(&output_dir, &from, &to, signing_key),
+        Some(Command::Keygen {
+            secret_key,
+            public_key,
+        }) => signing::generate_key_files(secret_key, public_key),
+        Some(Command::Verify { public_key, files }) => verify(&public_key, &files),
+        Some(Command::Estimate {
+            output_dir,
+            previous_outputs,
+            top,
+            unpacked_extension,
+        }) => estimate(&output_dir, &previous_outputs, top, unpacked_extension),
+        Some(Command::Update {
+            source,
+            install_dir,
+            unpacked_extension,
+            dry_run,
+            channel,
+            public_key,
+            repack_command,
+        }) => update(
+            &source,
+            &install_dir,
+            unpacked_extension,
+            dry_run,
+            channel,
+            public_key,
+            repack_command,
+        ),
+        Some(Command::Serve {
+            output_dir,
+            port,
+            bind,
+            latency_ms,
+            bandwidth,
+            failure_rate,
+        }) => serve::serve(
+            &PathBuf::from(output_dir),
+            ServeOptions {
+                // IPv6 addresses need brackets before the port, unless they
+                // were given with them
+                bind: match bind.contains(':') && !bind.starts_with('[') {
+                    true => format!("[{bind}]:{port}"),
+                    false => format!("{bind}:{port}"),
+                },
+                latency: Duration::from_millis(latency_ms),
+                bandwidth,
+                failure_rate,
+            },
+        ),
+        None => generate(cli.generate),
+    };
 
-    if let Err(why) =
-        aeco_patch_config::generate_config(args.eco_dir, args.output_dir, args.maintenance_mode)
-    {
+    if let Err(why) = result {
         eprintln!("{why:?}");
+        std::process::exit(1);
+    }
+}
+
+fn verify(public_key: &str, files: &[String]) -> Result<(), PatchConfigError> {
+    let public_key = signing::load_public_key(public_key)?;
+
+    for file in files {
+        signing::verify_file(file, &public_key)?;
+        println!("{file}: signature is valid");
+    }
+
+    Ok(())
+}
+
+fn generate(args: GenerateArgs) -> Result<(), PatchConfigError> {
+    // The folders are only optional so that subcommands can be used without
+    // them
+    let (eco_dir, output_dir) = match (args.eco_dir, args.output_dir) {
+        (Some(eco_dir), Some(output_dir)) => (eco_dir, output_dir),
+        _ => Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "An ECO folder and an output folder are required",
+            )
+            .exit(),
+    };
+
+    let mut options = GenerateOptions {
+        maintenance: args.maintenance_mode,
+        packed_archives: args.packed_archives,
+        signing_key: args.signing_key.map(PathBuf::from),
+        ignore: args.ignore,
+        protected: args.protected,
+        layers: args.layers.iter().map(PathBuf::from).collect(),
+        release: args.release,
+        previous_output: args.previous_output.map(PathBuf::from),
+        hash_cache: args.hash_cache.map(PathBuf::from),
+        windows_install_prefix: args.windows_install_prefix,
+        ..GenerateOptions::default()
+    };
+
+    if let (Some(path), Some(version)) = (args.launcher, args.launcher_version) {
+        options.launcher = Some(LauncherOptions {
+            path: PathBuf::from(path),
+            version,
+        });
+    }
+
+    options.budget = BudgetOptions {
+        max_delta_bytes: args.max_delta_bytes,
+        max_file_bytes: args.max_file_bytes,
+        max_total_bytes: args.max_total_bytes,
+        soft: args.soft_budget,
+    };
+
+    options.parallelism = ParallelismOptions {
+        threads: args.threads,
+        sequential: args.sequential,
+        max_concurrent_reads: args.max_concurrent_reads,
+        max_concurrent_writes: args.max_concurrent_writes,
+        largest_first: args.largest_first,
+    };
+
+    options.attributes.record_mtime = !args.no_mtime;
+    options.attributes.background = args.background;
+    options.attributes.executable.extend(args.executable);
+
+    for pack in &args.packs {
+        match pack.split_once('=') {
+            Some((name, glob)) => options
+                .packs
+                .entry(name.to_string())
+                .or_default()
+                .push(glob.to_string()),
+            None => Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("Pack {pack} should be given as NAME=GLOB"),
+                )
+                .exit(),
+        }
+    }
+
+    if args.content_addressed || args.channel.is_some() {
+        options.layout = OutputLayout::ContentAddressed;
+    }
+
+    if args.hardlink {
+        options.link_mode = LinkMode::Hardlink;
+    } else if args.reflink {
+        options.link_mode = LinkMode::ReflinkOrCopy;
+    }
+
+    options.compression.codecs = args
+        .codecs
+        .iter()
+        .map(|codec| match codec.as_str() {
+            "gzip" => Codec::Gzip,
+            "zstd" => Codec::Zstd,
+            _ => Codec::Brotli,
+        })
+        .collect();
+    options.compression.keep_uncompressed = !args.compressed_only;
+
+    if !args.no_compress_extensions.is_empty() {
+        options.compression.skip_extensions = args.no_compress_extensions;
+    }
+
+    if args.merge_unpacked_archives {
+        options.archive_conflicts = ArchiveConflictPolicy::MergeUnpacked;
+    }
+
+    if !args.data_extensions.is_empty() {
+        options.archive_extensions.data = args.data_extensions;
+    }
+
+    if !args.metadata_extensions.is_empty() {
+        options.archive_extensions.metadata = args.metadata_extensions;
+    }
+
+    if !args.unpacked_extensions.is_empty() {
+        options.archive_extensions.unpacked = args.unpacked_extensions;
+    }
+
+    let report = match &args.channel {
+        Some(channel) => channel::generate_channel(&eco_dir, &output_dir, channel, &options)?,
+        None => aeco_patch_config::generate_config(eco_dir, output_dir, &options)?,
+    };
+    print_report(&report);
+
+    write_reports(&report, &args.report)
+}
+
+fn build(config: &str, report_args: &ReportArgs) -> Result<(), PatchConfigError> {
+    let project = ProjectConfig::load(config)?;
+
+    let report = match &project.channel {
+        Some(channel) => {
+            channel::generate_channel(&project.source, &project.output, channel, &project.options)?
+        }
+        None => {
+            aeco_patch_config::generate_config(&project.source, &project.output, &project.options)?
+        }
+    };
+    print_report(&report);
+
+    write_reports(&report, report_args)
+}
+
+fn write_reports(report: &GenerationReport, args: &ReportArgs) -> Result<(), PatchConfigError> {
+    let write = |path: &str, text: &str| {
+        std::fs::write(path, text).map_err(|why| {
+            PatchConfigError::WriteTargetFileFailed(format!("Failed to write report {path}: {why}"))
+        })
+    };
+
+    if let Some(path) = &args.report_json {
+        write(path, &report.to_json()?)?;
+    }
+
+    if let Some(path) = &args.report_markdown {
+        write(path, &report.to_markdown())?;
+    }
+
+    if let Some(path) = &args.report_html {
+        write(path, &report.to_html())?;
+    }
+
+    Ok(())
+}
+
+fn promote(
+    output_dir: &str,
+    from: &str,
+    to: &str,
+    signing_key: Option<String>,
+) -> Result<(), PatchConfigError> {
+    let options = GenerateOptions {
+        signing_key: signing_key.map(PathBuf::from),
+        ..GenerateOptions::default()
+    };
+
+    let release = channel::promote_channel(output_dir, from, to, &options)?;
+    println!("Promoted release {} from {from} to {to}", release.id);
+
+    Ok(())
+}
+
+fn estimate(
+    output_dir: &str,
+    previous_outputs: &[String],
+    top: usize,
+    unpacked_extension: Option<String>,
+) -> Result<(), PatchConfigError> {
+    let extensions = archive_extensions(unpacked_extension);
+    let previous_outputs = previous_outputs
+        .iter()
+        .map(PathBuf::from)
+        .collect::<Vec<PathBuf>>();
+
+    let estimates = estimate::estimate_updates(
+        PathBuf::from(output_dir),
+        &previous_outputs,
+        &extensions,
+        top,
+    )?;
+
+    for estimate in estimates {
+        println!(
+            "From release {}: {} files, {} bytes",
+            estimate.from, estimate.files, estimate.bytes
+        );
+        for download in &estimate.largest {
+            println!("    {} ({} bytes)", download.path, download.bytes);
+        }
+    }
+
+    Ok(())
+}
+
+fn archive_extensions(unpacked_extension: Option<String>) -> ArchiveExtensions {
+    let mut extensions = ArchiveExtensions::default();
+    if let Some(unpacked_extension) = unpacked_extension {
+        extensions.unpacked = vec![unpacked_extension];
+    }
+    extensions
+}
+
+fn update(
+    source: &str,
+    install_dir: &str,
+    unpacked_extension: Option<String>,
+    dry_run: bool,
+    channel: Option<String>,
+    public_key: Option<String>,
+    repack_command: Option<String>,
+) -> Result<(), PatchConfigError> {
+    let options = ClientOptions {
+        channel,
+        public_key: public_key.map(signing::load_public_key).transpose()?,
+        packer: repack_command.map(|program| {
+            Box::new(CommandPacker {
+                program: PathBuf::from(program),
+            }) as Box<dyn ArchivePacker>
+        }),
+    };
+
+    let extensions = archive_extensions(unpacked_extension);
+    let client = PatchClient::with_options(PatchSource::new(source), extensions, options);
+    let release = client.fetch_release()?;
+
+    if let ServerStatus::Maintenance = release.status {
+        println!("The server is in maintenance mode");
+    }
+
+    let install_dir = PathBuf::from(install_dir);
+    let plan = client.plan(&release, &install_dir)?;
+
+    for download in &plan.downloads {
+        println!("Download {}", download.path);
+    }
+    for repack in &plan.repacks {
+        println!(
+            "Repack {} ({} entries to download)",
+            repack.path,
+            repack.downloads.len()
+        );
+    }
+    for path in &plan.removals {
+        println!("Remove {path}");
+    }
+    println!(
+        "{} files to download, {} archives to repack, {} to remove, {} up to date",
+        plan.downloads.len(),
+        plan.repacks.len(),
+        plan.removals.len(),
+        plan.up_to_date
+    );
+
+    if !dry_run {
+        client.apply(&plan, &install_dir)?;
+        println!("Install is up to date");
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &GenerationReport) {
+    println!("Generated release {}", report.release);
+
+    for warning in &report.warnings {
+        println!("Warning: {warning}");
+    }
+
+    println!(
+        "Wrote {} files ({} bytes)",
+        report.written_files, report.written_bytes
+    );
+
+    if report.linked_files > 0 {
+        println!(
+            "Linked {} files instead of copying them",
+            report.linked_files
+        );
+    }
+
+    if report.deduplicated_files > 0 {
+        println!(
+            "Deduplication skipped {} files, saving {} bytes",
+            report.deduplicated_files, report.deduplicated_bytes
+        );
+    }
+
+    let layer_name = |layer: usize| match report.layers.get(layer) {
+        Some(path) => path.to_string_lossy().to_string(),
+        None => layer.to_string(),
+    };
+
+    for (path, origin) in &report.origins {
+        if origin.overridden.is_empty() {
+            continue;
+        }
+
+        let overridden = origin
+            .overridden
+            .iter()
+            .map(|&layer| layer_name(layer))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        println!(
+            "{path}: taken from {}, overriding {overridden}",
+            layer_name(origin.layer)
+        );
     }
 }
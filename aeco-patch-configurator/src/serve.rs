@@ -0,0 +1,393 @@
+use aeco_patch_config::{
+    channel::{self, CHANNELS_DIR_NAME},
+    error::PatchConfigError,
+    fsobject::{Directory, FSObject, File},
+    options::{ArchiveExtensions, OutputLayout},
+    release,
+};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// How the development server behaves, including the faults it injects
+pub struct ServeOptions {
+    /// Address to listen on, such as 127.0.0.1:8080
+    pub bind: String,
+    /// Delay before every response
+    pub latency: Duration,
+    /// Maximum bytes per second sent for each response
+    pub bandwidth: Option<u64>,
+    /// Fraction of requests, from 0 to 1, which fail with 503
+    pub failure_rate: f64,
+}
+
+struct ServerState {
+    output_dir: PathBuf,
+    /// Digests from the manifests, keyed by URL path without the leading /
+    etags: HashMap<String, String>,
+    options: ServeOptions,
+}
+
+/// Serves a generated output over HTTP for testing launchers. Runs until the
+/// process is stopped.
+pub fn serve(output_dir: &Path, options: ServeOptions) -> Result<(), PatchConfigError> {
+    if !output_dir.is_dir() {
+        return Err(PatchConfigError::ServeFailed(format!(
+            "Output is not a directory: {}",
+            output_dir.to_string_lossy()
+        )));
+    }
+
+    let etags = load_etags(output_dir)?;
+
+    let server = Server::http(&options.bind).map_err(|why| {
+        PatchConfigError::ServeFailed(format!("Unable to listen on {}: {why}", options.bind))
+    })?;
+
+    println!(
+        "Serving {} on http://{}",
+        output_dir.to_string_lossy(),
+        options.bind
+    );
+
+    let state = Arc::new(ServerState {
+        output_dir: output_dir.to_path_buf(),
+        etags,
+        options,
+    });
+
+    // Every request gets its own thread, so a throttled download doesn't hold
+    // up the others
+    for request in server.incoming_requests() {
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            let url = request.url().to_string();
+            if let Err(why) = handle_request(request, &state) {
+                eprintln!("Failed to respond to {url}: {why}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Collects the digest of every file the manifests in an output describe,
+/// including the manifests of release channels
+fn load_etags(output_dir: &Path) -> Result<HashMap<String, String>, PatchConfigError> {
+    let mut meta_dirs = vec![output_dir.join("meta")];
+
+    if let Ok(readdir) = std::fs::read_dir(output_dir.join(CHANNELS_DIR_NAME)) {
+        for entry in readdir.filter_map(|entry| entry.ok()) {
+            let channel = entry.file_name().to_string_lossy().to_string();
+            meta_dirs.push(channel::channel_meta_dir(output_dir, &channel));
+        }
+    }
+
+    let mut etags = HashMap::<String, String>::new();
+
+    for meta_dir in meta_dirs {
+        // A channel whose first release failed has no metadata
+        if !meta_dir.is_dir() {
+            continue;
+        }
+
+        let patchlist = release::load_patchlist(&meta_dir)?;
+        let layout = release::load_layout(&meta_dir)?;
+
+        let mut files = Vec::<(String, &File)>::new();
+        let extensions = layout.archive_extensions(&ArchiveExtensions::default());
+        collect_files(&patchlist, "patch", &extensions, &mut files);
+
+        for (path, file) in files {
+            let path = match layout.layout {
+                OutputLayout::Tree => path,
+                OutputLayout::ContentAddressed => format!("objects/{}", file.digest),
+            };
+
+            for variant in &file.compressed {
+                etags.insert(
+                    format!("{path}.{}", variant.codec.extension()),
+                    variant.digest.clone(),
+                );
+            }
+
+            etags.insert(path, file.digest.clone());
+        }
+    }
+
+    Ok(etags)
+}
+
+fn collect_files<'a>(
+    dir: &'a Directory,
+    dir_path: &str,
+    extensions: &ArchiveExtensions,
+    files: &mut Vec<(String, &'a File)>,
+) {
+    for child in &dir.children {
+        match child {
+            FSObject::File(file) => files.push((format!("{dir_path}/{}", file.name), file)),
+            FSObject::Directory(child_dir) => {
+                collect_files(
+                    child_dir,
+                    &format!("{dir_path}/{}", child_dir.name),
+                    extensions,
+                    files,
+                );
+            }
+            FSObject::Archive(archive) => {
+                let archive_path = format!(
+                    "{dir_path}/{}.{}",
+                    archive.name,
+                    extensions.unpacked_output()
+                );
+                for file in &archive.files {
+                    files.push((format!("{archive_path}/{}", file.name), file));
+                }
+            }
+            FSObject::PackedArchive(archive) => {
+                for file in [&archive.header, &archive.data] {
+                    files.push((format!("{dir_path}/{}", file.name), file));
+                }
+            }
+        }
+    }
+}
+
+fn handle_request(request: Request, state: &ServerState) -> std::io::Result<()> {
+    if !state.options.latency.is_zero() {
+        thread::sleep(state.options.latency);
+    }
+
+    if *request.method() != Method::Get && *request.method() != Method::Head {
+        return request.respond(Response::empty(405).with_header(header("Allow", "GET, HEAD")));
+    }
+
+    if random_fraction() < state.options.failure_rate {
+        return request.respond(Response::from_string("Injected failure").with_status_code(503));
+    }
+
+    let relative_path = match url_to_relative_path(request.url()) {
+        Some(x) => x,
+        None => return request.respond(Response::empty(400)),
+    };
+
+    // Only files are served; directories aren't listed
+    let file_path = state.output_dir.join(&relative_path);
+    if !file_path.is_file() {
+        return request.respond(Response::from_string("Not found").with_status_code(404));
+    }
+
+    let mut file = std::fs::File::open(&file_path)?;
+    let size = file.metadata()?.len();
+
+    let digest = match state.etags.get(&relative_path) {
+        Some(digest) => digest.clone(),
+        None => {
+            let info = File::from_reader(&relative_path, BufReader::new(&mut file))?;
+            file.seek(SeekFrom::Start(0))?;
+            info.digest
+        }
+    };
+    let etag = format!("\"{digest}\"");
+
+    let request_header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str().to_string())
+    };
+
+    if request_header("If-None-Match").is_some_and(|value| {
+        value
+            .split(',')
+            .any(|candidate| candidate.trim() == etag || candidate.trim() == "*")
+    }) {
+        return request.respond(Response::empty(304).with_header(header("ETag", &etag)));
+    }
+
+    let (status, start, length) = match request_header("Range") {
+        None => (200, 0, size),
+        Some(range) => match parse_range(&range, size) {
+            Some((start, end)) => (206, start, end - start + 1),
+            None => {
+                return request.respond(
+                    Response::empty(416)
+                        .with_header(header("Content-Range", &format!("bytes */{size}"))),
+                );
+            }
+        },
+    };
+
+    file.seek(SeekFrom::Start(start))?;
+    let body = Throttled::new(file.take(length), state.options.bandwidth);
+
+    let mut headers = vec![
+        header("Content-Type", content_type(&relative_path)),
+        header("ETag", &etag),
+        header("Accept-Ranges", "bytes"),
+    ];
+    if status == 206 {
+        headers.push(header(
+            "Content-Range",
+            &format!("bytes {start}-{}/{size}", start + length - 1),
+        ));
+    }
+
+    request.respond(Response::new(
+        status.into(),
+        headers,
+        body,
+        Some(length as usize),
+        None,
+    ))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    // Every header used here is plain ASCII
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("header should be ASCII")
+}
+
+/// Turns a request URL into a path relative to the output, refusing anything
+/// which could escape it
+fn url_to_relative_path(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let path = percent_decode(path)?;
+    let path = path.trim_start_matches('/');
+
+    if path.is_empty() || path.contains('\\') {
+        return None;
+    }
+
+    let all_normal = Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+
+    if all_normal {
+        Some(path.to_string())
+    } else {
+        None
+    }
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = text.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Parses a single byte range, returning the first and last byte it covers.
+/// Multiple ranges aren't supported.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') || size == 0 {
+        return None;
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last n bytes
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?.min(size);
+            if suffix == 0 {
+                return None;
+            }
+            (size - suffix, size - 1)
+        }
+        (start, "") => (start.parse::<u64>().ok()?, size - 1),
+        (start, end) => (
+            start.parse::<u64>().ok()?,
+            end.parse::<u64>().ok()?.min(size - 1),
+        ),
+    };
+
+    if start > end || start >= size {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("json") => "application/json",
+        Some("sig") | Some("txt") => "text/plain; charset=utf-8",
+        Some("html") => "text/html; charset=utf-8",
+        Some("gz") => "application/gzip",
+        Some("zst") => "application/zstd",
+        Some("br") => "application/x-brotli",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A random number from 0 up to 1, good enough for injecting failures
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Limits how fast data is read, and therefore sent
+struct Throttled<R> {
+    inner: R,
+    bytes_per_second: Option<u64>,
+    started: Instant,
+    sent: u64,
+}
+
+impl<R> Throttled<R> {
+    fn new(inner: R, bytes_per_second: Option<u64>) -> Self {
+        Self {
+            inner,
+            bytes_per_second,
+            started: Instant::now(),
+            sent: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_per_second = match self.bytes_per_second {
+            Some(x) if x > 0 => x,
+            _ => return self.inner.read(buffer),
+        };
+
+        // Send small chunks so the rate stays even
+        let chunk = ((bytes_per_second / 10).max(1) as usize).min(buffer.len());
+        let count = self.inner.read(&mut buffer[..chunk])?;
+        self.sent += count as u64;
+
+        let due = Duration::from_secs_f64(self.sent as f64 / bytes_per_second as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+
+        Ok(count)
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{
+    context::Context,
+    diff,
+    error::PatchConfigError,
+    estimate,
+    fsobject::Directory,
+    options::{ArchiveExtensions, BudgetOptions},
+};
+use std::{collections::BTreeMap, path::Path};
+
+/// How many paths are named when a budget is exceeded
+const LARGEST_PATHS: usize = 10;
+
+/// Checks a generated patch tree against the budget, comparing it with the
+/// files of the previous release if there is one. Describes every budget
+/// which was exceeded along with the paths which contributed most. An empty
+/// result means the tree is within budget.
+pub fn budget_problems(
+    root: &Directory,
+    previous_files: Option<&BTreeMap<String, String>>,
+    budget: &BudgetOptions,
+    extensions: &ArchiveExtensions,
+) -> Vec<String> {
+    let mut problems = Vec::<String>::new();
+
+    let mut files = diff::flatten_files(root, extensions)
+        .into_iter()
+        .map(|(path, file)| (path, file.size))
+        .collect::<Vec<(String, u64)>>();
+    files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    if let Some(max_file_bytes) = budget.max_file_bytes {
+        for (path, size) in files.iter().take_while(|(_, size)| *size > max_file_bytes) {
+            problems.push(format!(
+                "{path} is {size} bytes, over the limit of {max_file_bytes} bytes per file"
+            ));
+        }
+    }
+
+    if let Some(max_total_bytes) = budget.max_total_bytes {
+        let total = files.iter().map(|(_, size)| size).sum::<u64>();
+        if total > max_total_bytes {
+            problems.push(format!(
+                "The patch is {total} bytes, over the limit of {max_total_bytes} bytes. \
+                 Largest files:\n{}",
+                list_paths(&files)
+            ));
+        }
+    }
+
+    if let (Some(max_delta_bytes), Some(previous_files)) = (budget.max_delta_bytes, previous_files)
+    {
+        let estimate = estimate::estimate_from_files(
+            "previous",
+            previous_files,
+            root,
+            extensions,
+            LARGEST_PATHS,
+        );
+
+        if estimate.bytes > max_delta_bytes {
+            let downloads = estimate
+                .largest
+                .into_iter()
+                .map(|download| (download.path, download.bytes))
+                .collect::<Vec<(String, u64)>>();
+
+            problems.push(format!(
+                "Updating from the previous release downloads {} bytes in {} files, over the \
+                 limit of {max_delta_bytes} bytes. Largest downloads:\n{}",
+                estimate.bytes,
+                estimate.files,
+                list_paths(&downloads)
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Checks a file against the per-file and total limits before it is written,
+/// so that a patch which is over budget fails before filling the output. Only
+/// files which are published may be checked, which holds since overrides
+/// between layers and archive sources are settled while planning, so every
+/// file is written once. The same files make up the tree which budgets that
+/// only warn are checked against once it is complete, by [`budget_problems`],
+/// which also names the largest files.
+pub(crate) fn check_file(
+    context: &Context,
+    target_path: &Path,
+    size: u64,
+) -> Result<(), PatchConfigError> {
+    let budget = &context.options.budget;
+    let total = context.output_stats.record_published(size);
+
+    if budget.soft {
+        return Ok(());
+    }
+
+    if let Some(max_file_bytes) = budget.max_file_bytes.filter(|max| size > *max) {
+        return Err(PatchConfigError::BudgetExceeded(format!(
+            "The patch exceeds its budget:\n{} is {size} bytes, over the limit of \
+             {max_file_bytes} bytes per file",
+            target_path.to_string_lossy()
+        )));
+    }
+
+    if let Some(max_total_bytes) = budget.max_total_bytes.filter(|max| total > *max) {
+        return Err(PatchConfigError::BudgetExceeded(format!(
+            "The patch exceeds its budget:\nThe patch is over the limit of {max_total_bytes} \
+             bytes by the time {} is added",
+            target_path.to_string_lossy()
+        )));
+    }
+
+    Ok(())
+}
+
+fn list_paths(paths: &[(String, u64)]) -> String {
+    paths
+        .iter()
+        .take(LARGEST_PATHS)
+        .map(|(path, size)| format!("    {path} ({size} bytes)"))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
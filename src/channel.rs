@@ -0,0 +1,348 @@
+use crate::{
+    context::{self, Context},
+    deletions::{self, Deletions},
+    diff,
+    error::PatchConfigError,
+    options::{GenerateOptions, OutputLayout},
+    release::{self, Release},
+    report::GenerationReport,
+    signing,
+    sink::DiskSink,
+    source::Sources,
+    DELETIONS_FILE_NAME, LAUNCHER_FILE_NAME, LAYOUT_FILE_NAME, METADATA_DIR_NAME, PACKS_FILE_NAME,
+    PATCHLIST_FILE_NAME, RELEASE_FILE_NAME, RELEASE_HISTORY_FILE_NAME, STATUS_FILE_NAME,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// Directory of the output which holds a directory for each channel
+pub const CHANNELS_DIR_NAME: &str = "channels";
+
+/// Suffix of the metadata directory a channel is generated into before it
+/// replaces the channel's current metadata
+const STAGING_SUFFIX: &str = "new";
+
+/// Suffix the replaced metadata directory is moved to until it is removed
+const REPLACED_SUFFIX: &str = "old";
+
+/// The metadata directory of a channel, such as channels/live/meta
+pub fn channel_meta_dir(output_dir: &Path, channel: &str) -> PathBuf {
+    output_dir
+        .join(CHANNELS_DIR_NAME)
+        .join(channel)
+        .join(METADATA_DIR_NAME)
+}
+
+/// Generates a release for one channel of an output which holds several, such
+/// as live and test. Every channel has its own metadata, but file contents are
+/// stored once in the shared object directory. The output is created if it
+/// doesn't exist yet.
+///
+/// The channel's current metadata is replaced once the release is complete.
+/// Unless another previous output is given, the channel's current release is
+/// treated as the previous release.
+pub fn generate_channel<P>(
+    source_dir: P,
+    output_dir: P,
+    channel: &str,
+    options: &GenerateOptions,
+) -> Result<GenerationReport, PatchConfigError>
+where
+    P: AsRef<Path>,
+{
+    let source_dir = source_dir.as_ref();
+    let output_dir = output_dir.as_ref();
+
+    crate::check_source_dirs(source_dir, options)?;
+
+    // Sharing storage between channels only works when files are stored by
+    // digest rather than by path
+    if options.layout != OutputLayout::ContentAddressed {
+        return Err(PatchConfigError::ChannelFailed(
+            "Channels require the content-addressed layout".to_string(),
+        ));
+    }
+
+    check_channel_name(channel)?;
+
+    let meta_dir = channel_meta_dir(output_dir, channel);
+    let previous_meta_dir = match &options.previous_output {
+        Some(previous_output) => Some(previous_output.join(METADATA_DIR_NAME)),
+        None if meta_dir.exists() => Some(meta_dir.clone()),
+        None => None,
+    };
+
+    let sources = Sources::open(&context::source_roots(source_dir, options))?;
+    let context = Context::new(options, source_dir, output_dir, &sources, &DiskSink)?;
+    // A channel continues its own history, so there is nothing to warn about
+    let prepared = crate::prepare_release(previous_meta_dir.as_deref(), None, options)?;
+
+    let staging_dir = create_staging_dir(&meta_dir)?;
+
+    let report = match crate::generate_release(output_dir, &staging_dir, prepared, &context) {
+        Ok(x) => x,
+        Err(why) => {
+            // Other channels keep their releases, so only what this one wrote
+            // is removed
+            let _ = std::fs::remove_dir_all(&staging_dir);
+            context.discard_new_objects();
+            return Err(why);
+        }
+    };
+
+    replace_dir(&staging_dir, &meta_dir)?;
+
+    Ok(report)
+}
+
+/// Makes the release of one channel the release of another, such as staging
+/// to live, without hashing anything again. The manifest is copied as it is,
+/// along with its signature. The target channel keeps its own status, and
+/// the promotion is appended to its release history.
+pub fn promote_channel<P>(
+    output_dir: P,
+    from_channel: &str,
+    to_channel: &str,
+    options: &GenerateOptions,
+) -> Result<Release, PatchConfigError>
+where
+    P: AsRef<Path>,
+{
+    let output_dir = output_dir.as_ref();
+
+    check_channel_name(from_channel)?;
+    check_channel_name(to_channel)?;
+
+    if from_channel == to_channel {
+        return Err(PatchConfigError::ChannelFailed(format!(
+            "Can't promote channel {from_channel} to itself"
+        )));
+    }
+
+    let from_meta_dir = channel_meta_dir(output_dir, from_channel);
+    let to_meta_dir = channel_meta_dir(output_dir, to_channel);
+
+    if !from_meta_dir.is_dir() {
+        return Err(PatchConfigError::ChannelFailed(format!(
+            "Channel {from_channel} has not been generated in {}",
+            output_dir.to_string_lossy()
+        )));
+    }
+
+    let signing_key = match &options.signing_key {
+        Some(path) => Some(signing::load_signing_key(path)?),
+        None => None,
+    };
+
+    let promoted = release::load_release(&from_meta_dir)?;
+
+    // The target channel's history decides whether the release is newer, and
+    // its current files are what the promotion changes
+    let (mut history, previous_files, previous_deletions) = if to_meta_dir.is_dir() {
+        (
+            release::load_history(&to_meta_dir)?,
+            diff::flatten(
+                &release::load_patchlist(&to_meta_dir)?,
+                &options.archive_extensions,
+            ),
+            deletions::load_deletions(&to_meta_dir)?,
+        )
+    } else {
+        (Vec::new(), BTreeMap::new(), Deletions::default())
+    };
+
+    release::next_release_id(Some(&promoted.id), &history)?;
+
+    let promoted_files = diff::flatten(
+        &release::load_patchlist(&from_meta_dir)?,
+        &options.archive_extensions,
+    );
+
+    let staging_dir = create_staging_dir(&to_meta_dir)?;
+
+    copy_metadata(&from_meta_dir, &staging_dir, PATCHLIST_FILE_NAME)?;
+    copy_metadata(&from_meta_dir, &staging_dir, LAYOUT_FILE_NAME)?;
+    copy_metadata(&from_meta_dir, &staging_dir, PACKS_FILE_NAME)?;
+
+    // Not every release publishes a launcher
+    if from_meta_dir.join(LAUNCHER_FILE_NAME).exists() {
+        copy_metadata(&from_meta_dir, &staging_dir, LAUNCHER_FILE_NAME)?;
+    }
+
+    // A new channel starts out with the status of the channel it came from
+    let status_dir = if to_meta_dir.join(STATUS_FILE_NAME).exists() {
+        &to_meta_dir
+    } else {
+        &from_meta_dir
+    };
+    copy_metadata(status_dir, &staging_dir, STATUS_FILE_NAME)?;
+
+    let release = Release {
+        id: promoted.id,
+        root_digest: promoted.root_digest,
+        timestamp: release::timestamp(),
+        changes: diff::ChangeSummary::between(&previous_files, &promoted_files),
+    };
+
+    // Tombstones describe what the target channel's clients have installed,
+    // but the protected paths are the ones the release was generated with
+    let deletions = deletions::update_deletions(
+        previous_deletions,
+        &previous_files,
+        &promoted_files,
+        &release.id,
+        &deletions::load_deletions(&from_meta_dir)?.protected,
+    )?;
+
+    let signing_key = signing_key.as_ref();
+    crate::write_metadata(
+        &DiskSink,
+        &staging_dir,
+        DELETIONS_FILE_NAME,
+        &deletions,
+        signing_key,
+    )?;
+    crate::write_metadata(
+        &DiskSink,
+        &staging_dir,
+        RELEASE_FILE_NAME,
+        &release,
+        signing_key,
+    )?;
+
+    history.push(release::HistoryEntry::from(&release));
+    crate::write_metadata(
+        &DiskSink,
+        &staging_dir,
+        RELEASE_HISTORY_FILE_NAME,
+        &history,
+        signing_key,
+    )?;
+
+    replace_dir(&staging_dir, &to_meta_dir)?;
+
+    Ok(release)
+}
+
+/// Channel names become directory names, so they must be plain names
+pub(crate) fn check_channel_name(channel: &str) -> Result<(), PatchConfigError> {
+    let valid = !channel.is_empty()
+        && channel != "."
+        && channel != ".."
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(PatchConfigError::ChannelFailed(format!(
+            "Invalid channel name {channel:?}, use letters, digits, '-', '_' and '.'"
+        )))
+    }
+}
+
+/// Decides where the replacement of a metadata directory is written, next to
+/// it, and removes any leftovers of an earlier failed run there
+fn create_staging_dir(meta_dir: &Path) -> Result<PathBuf, PatchConfigError> {
+    let staging_dir = meta_dir.with_extension(STAGING_SUFFIX);
+
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir).map_err(|why| {
+            PatchConfigError::MetadataDirectoryFailed(format!(
+                "Unable to remove leftover metadata directory {}: {}",
+                staging_dir.to_string_lossy(),
+                why
+            ))
+        })?;
+    }
+
+    if let Some(channel_dir) = staging_dir.parent() {
+        std::fs::create_dir_all(channel_dir).map_err(|why| {
+            PatchConfigError::CreateTargetDirectoryFailed(format!(
+                "Unable to create channel directory {}: {}",
+                channel_dir.to_string_lossy(),
+                why
+            ))
+        })?;
+    }
+
+    // The staging directory itself is created along with its first file
+    Ok(staging_dir)
+}
+
+/// Swaps a finished staging directory in for a metadata directory
+fn replace_dir(staging_dir: &Path, meta_dir: &Path) -> Result<(), PatchConfigError> {
+    let rename_error = |from: &Path, to: &Path, why: std::io::Error| {
+        PatchConfigError::MetadataDirectoryFailed(format!(
+            "Unable to move metadata directory {} to {}: {}",
+            from.to_string_lossy(),
+            to.to_string_lossy(),
+            why
+        ))
+    };
+
+    // Directories can't be renamed over each other, so move the old one out
+    // of the way first
+    let old_dir = meta_dir.with_extension(REPLACED_SUFFIX);
+    if meta_dir.exists() {
+        if old_dir.exists() {
+            std::fs::remove_dir_all(&old_dir)
+                .map_err(|why| rename_error(meta_dir, &old_dir, why))?;
+        }
+        std::fs::rename(meta_dir, &old_dir).map_err(|why| rename_error(meta_dir, &old_dir, why))?;
+    }
+
+    std::fs::rename(staging_dir, meta_dir)
+        .map_err(|why| rename_error(staging_dir, meta_dir, why))?;
+
+    if old_dir.exists() {
+        std::fs::remove_dir_all(&old_dir).map_err(|why| {
+            PatchConfigError::MetadataDirectoryFailed(format!(
+                "Unable to remove old metadata directory {}: {}",
+                old_dir.to_string_lossy(),
+                why
+            ))
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Copies a metadata file between directories unchanged, along with its
+/// signature if it has one
+fn copy_metadata(from_dir: &Path, to_dir: &Path, file_name: &str) -> Result<(), PatchConfigError> {
+    if !to_dir.exists() {
+        std::fs::create_dir(to_dir).map_err(|why| {
+            PatchConfigError::MetadataDirectoryFailed(format!(
+                "Unable to create metadata directory {}: {}",
+                to_dir.to_string_lossy(),
+                why
+            ))
+        })?;
+    }
+
+    let from_path = from_dir.join(file_name);
+    let to_path = to_dir.join(file_name);
+
+    let mut copies = vec![(from_path.clone(), to_path.clone())];
+    let from_signature = signing::signature_path(&from_path);
+    if from_signature.exists() {
+        copies.push((from_signature, signing::signature_path(&to_path)));
+    }
+
+    for (from, to) in copies {
+        std::fs::copy(&from, &to).map_err(|why| {
+            PatchConfigError::WriteMetadataFailed(format!(
+                "Unable to copy metadata {} to {}: {}",
+                from.to_string_lossy(),
+                to.to_string_lossy(),
+                why
+            ))
+        })?;
+    }
+
+    Ok(())
+}
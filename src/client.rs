@@ -0,0 +1,1237 @@
+use crate::{
+    channel,
+    compression::Codec,
+    context::OBJECTS_DIR_NAME,
+    deletions::Deletions,
+    diff,
+    error::PatchConfigError,
+    fsobject::{self, Archive, Directory, FSObject, File},
+    options::{ArchiveExtensions, OutputLayout, PublishedLayout},
+    signing,
+    status::ServerStatus,
+    DELETIONS_FILE_NAME, LAYOUT_FILE_NAME, METADATA_DIR_NAME, PATCHLIST_FILE_NAME, PATCH_DIR_NAME,
+    STATUS_FILE_NAME,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+/// Directory of the install which holds partial downloads. Downloads are
+/// named by digest, so an interrupted update resumes where it stopped.
+pub const DOWNLOADS_DIR_NAME: &str = ".aeco-downloads";
+
+/// A file of the output being read, and the offset it is read from
+struct OpenedFile {
+    reader: Box<dyn Read + Send>,
+    start: u64,
+}
+
+/// Where an output is read from
+#[derive(Debug, Clone)]
+pub enum PatchSource {
+    /// Base URL of an output served over HTTP, such as http://127.0.0.1:8080
+    Http(String),
+    /// An output on disk
+    Local(PathBuf),
+}
+
+impl PatchSource {
+    /// Treats locations starting with http:// or https:// as URLs, and
+    /// anything else as a path
+    pub fn new(location: &str) -> Self {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            PatchSource::Http(location.trim_end_matches('/').to_string())
+        } else {
+            PatchSource::Local(PathBuf::from(location))
+        }
+    }
+
+    /// Opens a file of the output, starting from an offset. The source may
+    /// ignore the offset and start from 0 instead.
+    fn open(&self, path: &str, offset: u64) -> Result<Option<OpenedFile>, PatchConfigError> {
+        match self {
+            PatchSource::Http(base) => {
+                let url = format!("{base}/{}", encode_url_path(path));
+                let mut request = ureq::get(&url);
+                if offset > 0 {
+                    request = request.set("Range", &format!("bytes={offset}-"));
+                }
+
+                match request.call() {
+                    Ok(response) => {
+                        let start = if response.status() == 206 { offset } else { 0 };
+                        Ok(Some(OpenedFile {
+                            reader: Box::new(response.into_reader()),
+                            start,
+                        }))
+                    }
+                    Err(ureq::Error::Status(404, _)) => Ok(None),
+                    // The partial download is no longer valid, such as when
+                    // it is already complete
+                    Err(ureq::Error::Status(416, _)) if offset > 0 => self.open(path, 0),
+                    Err(why) => Err(PatchConfigError::ClientFailed(format!(
+                        "Unable to download {url}: {why}"
+                    ))),
+                }
+            }
+            PatchSource::Local(root) => {
+                let file_path = root.join(path);
+                if !file_path.is_file() {
+                    return Ok(None);
+                }
+
+                let mut file = std::fs::File::open(&file_path).map_err(|why| {
+                    PatchConfigError::ClientFailed(format!(
+                        "Unable to read {}: {}",
+                        file_path.to_string_lossy(),
+                        why
+                    ))
+                })?;
+
+                let start = file.seek(SeekFrom::Start(offset)).unwrap_or(0);
+                Ok(Some(OpenedFile {
+                    reader: Box::new(file),
+                    start,
+                }))
+            }
+        }
+    }
+
+    /// Reads a whole file of the output, if it exists
+    fn read(&self, path: &str) -> Result<Option<Vec<u8>>, PatchConfigError> {
+        let mut reader = match self.open(path, 0)? {
+            Some(opened) => opened.reader,
+            None => return Ok(None),
+        };
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map_err(|why| {
+            PatchConfigError::ClientFailed(format!("Unable to read {path}: {why}"))
+        })?;
+
+        Ok(Some(data))
+    }
+}
+
+/// Writes the HED and DAT of archives which a client repacks from their
+/// entries. The format is owned by the game, so the packer is supplied by
+/// whoever embeds the client, such as [`CommandPacker`].
+pub trait ArchivePacker: Send + Sync {
+    /// Writes an archive holding every file of a directory, named by its path
+    /// within the directory
+    fn pack(&self, entries_dir: &Path, header_path: &Path, data_path: &Path) -> io::Result<()>;
+
+    /// Visits every entry of an archive which is already installed
+    fn read_entries(
+        &self,
+        header_path: &Path,
+        data_path: &Path,
+        visit: &mut dyn FnMut(&str, &[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let archive = aeco_archive::Archive::open_pair(data_path, header_path)
+            .map_err(|why| io::Error::other(format!("{why:?}")))?;
+
+        for name in archive.file_names() {
+            let data = archive
+                .get_file(name)
+                .map_err(|why| io::Error::other(format!("{why:?}")))?;
+            visit(name, &data)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Packs archives by running an external program as
+/// `<program> <entries directory> <HED> <DAT>`, such as a packer from the
+/// game's tools
+pub struct CommandPacker {
+    pub program: PathBuf,
+}
+
+impl ArchivePacker for CommandPacker {
+    fn pack(&self, entries_dir: &Path, header_path: &Path, data_path: &Path) -> io::Result<()> {
+        let status = std::process::Command::new(&self.program)
+            .arg(entries_dir)
+            .arg(header_path)
+            .arg(data_path)
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "{} exited with {status}",
+                self.program.to_string_lossy()
+            )))
+        }
+    }
+}
+
+/// How a client reads an output and installs it
+#[derive(Default)]
+pub struct ClientOptions {
+    /// Channel to follow, such as live, rather than the output's own release
+    pub channel: Option<String>,
+    /// Public key which every metadata file must be signed with. Metadata
+    /// without a valid signature is rejected before any of it is used.
+    pub public_key: Option<[u8; 32]>,
+    /// Repacks unpacked archives into their HED and DAT rather than
+    /// installing their entries as loose files
+    pub packer: Option<Box<dyn ArchivePacker>>,
+}
+
+/// The metadata of an output which a client needs to update an install
+#[derive(Debug)]
+pub struct RemoteRelease {
+    pub status: ServerStatus,
+    pub layout: PublishedLayout,
+    pub patchlist: Directory,
+    pub deletions: Deletions,
+}
+
+/// What has to happen to bring an install up to date
+#[derive(Debug, Default)]
+pub struct UpdatePlan {
+    pub downloads: Vec<PlannedDownload>,
+    /// Archives which are repacked, when the client has a packer
+    pub repacks: Vec<PlannedRepack>,
+    /// Files removed from the patch which are still installed
+    pub removals: Vec<String>,
+    /// Number of files which are already up to date
+    pub up_to_date: u64,
+}
+
+/// A file which is missing from the install or differs from the patch
+#[derive(Debug, Clone)]
+pub struct PlannedDownload {
+    /// Path relative to the install directory, or to the archive for the
+    /// entries of a repacked archive
+    pub path: String,
+    /// Digest of the installed file
+    pub digest: String,
+    /// Path of the file to download, relative to the output
+    pub object: String,
+    /// Digest of the file to download, which differs from the installed
+    /// file's digest when it is compressed
+    pub object_digest: String,
+    /// How the download is compressed, if it is
+    pub codec: Option<Codec>,
+    pub mtime: Option<u64>,
+    pub executable: bool,
+}
+
+/// An archive whose installed HED and DAT are missing or out of date
+#[derive(Debug, Clone, Default)]
+pub struct PlannedRepack {
+    /// Path of the archive relative to the install directory, without an
+    /// extension, such as Data/music
+    pub path: String,
+    /// Path of the directory the archive is installed in when it isn't
+    /// repacked, such as Data/music.archive, which is removed once it is
+    pub unpacked_path: String,
+    /// Digests of every entry the repacked archive holds, by name
+    pub entries: BTreeMap<String, String>,
+    /// Entries which are downloaded, while the others are taken from the
+    /// installed archive
+    pub downloads: Vec<PlannedDownload>,
+}
+
+/// A reference client, which reads an output and brings installs up to date
+/// with it. Unpacked archives are installed as loose entries in their
+/// `.archive` directory, the paths deletion lists use, unless the client
+/// repacks them. Packed archives are installed as their HED and DAT.
+pub struct PatchClient {
+    source: PatchSource,
+    extensions: ArchiveExtensions,
+    options: ClientOptions,
+}
+
+impl PatchClient {
+    /// The archive extensions must be those the output was generated with,
+    /// except for the unpacked extension of outputs which record it
+    pub fn new(source: PatchSource, extensions: ArchiveExtensions) -> Self {
+        Self::with_options(source, extensions, ClientOptions::default())
+    }
+
+    pub fn with_options(
+        source: PatchSource,
+        extensions: ArchiveExtensions,
+        options: ClientOptions,
+    ) -> Self {
+        Self {
+            source,
+            extensions,
+            options,
+        }
+    }
+
+    /// Downloads the metadata of the current release
+    pub fn fetch_release(&self) -> Result<RemoteRelease, PatchConfigError> {
+        let meta_dir = match &self.options.channel {
+            Some(name) => {
+                channel::check_channel_name(name)?;
+                format!("{}/{name}/{METADATA_DIR_NAME}", channel::CHANNELS_DIR_NAME)
+            }
+            None => METADATA_DIR_NAME.to_string(),
+        };
+
+        let status = self.read_required_metadata(&meta_dir, STATUS_FILE_NAME)?;
+        let patchlist = self.read_required_metadata(&meta_dir, PATCHLIST_FILE_NAME)?;
+
+        // Outputs generated before these were written have none
+        let layout = self
+            .read_metadata(&meta_dir, LAYOUT_FILE_NAME)?
+            .unwrap_or_default();
+        let deletions = self
+            .read_metadata(&meta_dir, DELETIONS_FILE_NAME)?
+            .unwrap_or_default();
+
+        Ok(RemoteRelease {
+            status,
+            layout,
+            patchlist,
+            deletions,
+        })
+    }
+
+    /// Reads a metadata file, checking its signature first if the client has
+    /// a public key
+    fn read_metadata<T>(&self, meta_dir: &str, name: &str) -> Result<Option<T>, PatchConfigError>
+    where
+        T: DeserializeOwned,
+    {
+        let path = format!("{meta_dir}/{name}");
+        let data = match self.source.read(&path)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if let Some(public_key) = &self.options.public_key {
+            let signature_path = format!("{path}.{}", signing::SIGNATURE_EXTENSION);
+            let signature = match self.source.read(&signature_path)? {
+                Some(x) => String::from_utf8_lossy(&x).to_string(),
+                None => {
+                    return Err(PatchConfigError::SignatureInvalid(format!(
+                        "Metadata {path} is not signed"
+                    )));
+                }
+            };
+
+            signing::verify(&data, &signature, public_key).map_err(|why| {
+                PatchConfigError::SignatureInvalid(format!("{path}: {}", why.to_string()))
+            })?;
+        }
+
+        serde_json::from_slice(&data).map(Some).map_err(|why| {
+            PatchConfigError::ClientFailed(format!("Metadata {path} is invalid: {why}"))
+        })
+    }
+
+    fn read_required_metadata<T>(&self, meta_dir: &str, name: &str) -> Result<T, PatchConfigError>
+    where
+        T: DeserializeOwned,
+    {
+        match self.read_metadata(meta_dir, name)? {
+            Some(x) => Ok(x),
+            None => Err(PatchConfigError::ClientFailed(format!(
+                "The output has no {meta_dir}/{name}"
+            ))),
+        }
+    }
+
+    /// Compares an install with a release. Installed files are hashed, so
+    /// nothing is downloaded twice. Every path of the release is checked to
+    /// stay within the install before anything is planned.
+    pub fn plan(
+        &self,
+        release: &RemoteRelease,
+        install_dir: &Path,
+    ) -> Result<UpdatePlan, PatchConfigError> {
+        let mut plan = UpdatePlan::default();
+
+        // Archives are unpacked with the extension the output was generated
+        // with, which may not be the client's
+        let extensions = release.layout.archive_extensions(&self.extensions);
+
+        let mut archives = BTreeMap::<String, &Archive>::new();
+        if self.options.packer.is_some() {
+            collect_unpacked_archives(&release.patchlist, "", &mut archives);
+        }
+
+        // The entries of repacked archives are planned along with them
+        let unpacked_prefixes: Vec<String> = archives
+            .keys()
+            .map(|path| format!("{path}.{}/", extensions.unpacked_output()))
+            .collect();
+
+        for (path, file) in diff::flatten_files(&release.patchlist, &extensions) {
+            if unpacked_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix))
+            {
+                continue;
+            }
+
+            let file_path = install_path(install_dir, &path)?;
+            if installed_digest(&file_path)?.as_ref() == Some(&file.digest) {
+                plan.up_to_date += 1;
+                continue;
+            }
+
+            plan.downloads
+                .push(plan_download(&path, file, release.layout.layout)?);
+        }
+
+        if let Some(packer) = &self.options.packer {
+            for (path, archive) in archives {
+                self.plan_repack(
+                    packer.as_ref(),
+                    &path,
+                    archive,
+                    release,
+                    install_dir,
+                    &mut plan,
+                )?;
+            }
+        }
+
+        for tombstone in &release.deletions.tombstones {
+            if install_path(install_dir, &tombstone.path)?.is_file() {
+                plan.removals.push(tombstone.path.clone());
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Compares the entries of an installed archive with those of the patch
+    fn plan_repack(
+        &self,
+        packer: &dyn ArchivePacker,
+        path: &str,
+        archive: &Archive,
+        release: &RemoteRelease,
+        install_dir: &Path,
+        plan: &mut UpdatePlan,
+    ) -> Result<(), PatchConfigError> {
+        let (header_path, data_path) = self.packed_paths(install_dir, path)?;
+
+        let mut installed = BTreeMap::<String, String>::new();
+        if header_path.is_file() && data_path.is_file() {
+            let read = packer.read_entries(&header_path, &data_path, &mut |name, data| {
+                installed.insert(name.to_string(), fsobject::digest_of(data));
+                Ok(())
+            });
+
+            // An archive which can't be read is rebuilt from scratch
+            if read.is_err() {
+                installed.clear();
+            }
+        }
+
+        let extensions = release.layout.archive_extensions(&self.extensions);
+        let unpacked_path = format!("{path}.{}", extensions.unpacked_output());
+        let mut repack = PlannedRepack {
+            path: path.to_string(),
+            unpacked_path: unpacked_path.clone(),
+            ..PlannedRepack::default()
+        };
+
+        for file in &archive.files {
+            // Entries are written out to be packed, so their names must be
+            // as safe as any other path
+            install_path(install_dir, &diff::join(&unpacked_path, &file.name))?;
+
+            repack
+                .entries
+                .insert(file.name.clone(), file.digest.clone());
+
+            if installed.get(&file.name) != Some(&file.digest) {
+                let mut download = plan_download(
+                    &diff::join(&unpacked_path, &file.name),
+                    file,
+                    release.layout.layout,
+                )?;
+                download.path = file.name.clone();
+                repack.downloads.push(download);
+            }
+        }
+
+        if repack.downloads.is_empty() && repack.entries.len() == installed.len() {
+            plan.up_to_date += repack.entries.len() as u64;
+        } else {
+            plan.repacks.push(repack);
+        }
+
+        Ok(())
+    }
+
+    /// Where the HED and DAT of a repacked archive are installed
+    fn packed_paths(
+        &self,
+        install_dir: &Path,
+        path: &str,
+    ) -> Result<(PathBuf, PathBuf), PatchConfigError> {
+        Ok((
+            install_path(
+                install_dir,
+                &format!("{path}.{}", self.extensions.metadata_output()),
+            )?,
+            install_path(
+                install_dir,
+                &format!("{path}.{}", self.extensions.data_output()),
+            )?,
+        ))
+    }
+
+    /// Carries out a plan. Every download is checked against its digest
+    /// before it replaces an installed file. If this fails, calling it again
+    /// resumes the downloads which were interrupted.
+    pub fn apply(&self, plan: &UpdatePlan, install_dir: &Path) -> Result<(), PatchConfigError> {
+        let downloads_dir = install_dir.join(DOWNLOADS_DIR_NAME);
+        create_dir(&downloads_dir)?;
+
+        for download in &plan.downloads {
+            self.install(download, install_dir, &downloads_dir)?;
+        }
+
+        if !plan.repacks.is_empty() {
+            let packer = match &self.options.packer {
+                Some(x) => x.as_ref(),
+                None => {
+                    return Err(PatchConfigError::ClientFailed(
+                        "The plan repacks archives, but the client has no packer".to_string(),
+                    ));
+                }
+            };
+
+            for repack in &plan.repacks {
+                self.repack(packer, repack, install_dir, &downloads_dir)?;
+            }
+        }
+
+        for path in &plan.removals {
+            let file_path = install_path(install_dir, path)?;
+            std::fs::remove_file(&file_path).map_err(|why| {
+                PatchConfigError::ClientFailed(format!(
+                    "Unable to remove {}: {}",
+                    file_path.to_string_lossy(),
+                    why
+                ))
+            })?;
+        }
+
+        // Everything was installed, so nothing is left to resume
+        let _ = std::fs::remove_dir_all(&downloads_dir);
+
+        Ok(())
+    }
+
+    fn install(
+        &self,
+        download: &PlannedDownload,
+        install_dir: &Path,
+        downloads_dir: &Path,
+    ) -> Result<(), PatchConfigError> {
+        let data = self.fetch(download, downloads_dir)?;
+
+        let target_path = install_path(install_dir, &download.path)?;
+        if let Some(parent) = target_path.parent() {
+            create_dir(parent)?;
+        }
+
+        // Staged in the install first, so the installed file is never left
+        // half written
+        let staged_path = downloads_dir.join(format!("{}.staged", download.digest));
+        write_file(&staged_path, &data)?;
+        set_attributes(&staged_path, download)?;
+
+        // A directory may stand where the file now goes
+        if target_path.is_dir() {
+            remove_dir(&target_path)?;
+        }
+
+        rename(&staged_path, &target_path)?;
+
+        let _ = std::fs::remove_file(downloads_dir.join(&download.object_digest));
+
+        Ok(())
+    }
+
+    /// Builds the HED and DAT of an archive from the entries which are
+    /// downloaded and those kept from the installed archive. The new archive
+    /// is read back and checked against the digests of its entries before it
+    /// replaces the installed one.
+    fn repack(
+        &self,
+        packer: &dyn ArchivePacker,
+        repack: &PlannedRepack,
+        install_dir: &Path,
+        downloads_dir: &Path,
+    ) -> Result<(), PatchConfigError> {
+        let (header_path, data_path) = self.packed_paths(install_dir, &repack.path)?;
+        let archive_name = |path: &Path| path.to_string_lossy().to_string();
+
+        // Named by the archive's path, so that repacks don't share files
+        let staging_name = fsobject::digest_of(repack.path.as_bytes());
+        let entries_dir = downloads_dir.join(format!("{staging_name}.entries"));
+        let staged_header_path = downloads_dir.join(format!("{staging_name}.header"));
+        let staged_data_path = downloads_dir.join(format!("{staging_name}.data"));
+
+        // Left behind by an interrupted repack
+        if entries_dir.exists() {
+            remove_dir(&entries_dir)?;
+        }
+        create_dir(&entries_dir)?;
+
+        let mut kept = repack.entries.clone();
+        for download in &repack.downloads {
+            kept.remove(&download.path);
+        }
+
+        if !kept.is_empty() {
+            packer
+                .read_entries(&header_path, &data_path, &mut |name, data| {
+                    if kept.get(name) == Some(&fsobject::digest_of(data)) {
+                        let entry_path = entries_dir.join(name);
+                        if let Some(parent) = entry_path.parent() {
+                            std::fs::create_dir_all(parent)?;
+                        }
+                        std::fs::write(entry_path, data)?;
+                        kept.remove(name);
+                    }
+                    Ok(())
+                })
+                .map_err(|why| {
+                    PatchConfigError::ClientFailed(format!(
+                        "Unable to read archive {}: {}",
+                        archive_name(&header_path),
+                        why
+                    ))
+                })?;
+
+            if let Some(name) = kept.keys().next() {
+                return Err(PatchConfigError::ClientFailed(format!(
+                    "Entry {name} is no longer in archive {}",
+                    archive_name(&header_path)
+                )));
+            }
+        }
+
+        for download in &repack.downloads {
+            let data = self.fetch(download, downloads_dir)?;
+
+            let entry_path = install_path(&entries_dir, &download.path)?;
+            if let Some(parent) = entry_path.parent() {
+                create_dir(parent)?;
+            }
+            write_file(&entry_path, &data)?;
+        }
+
+        packer
+            .pack(&entries_dir, &staged_header_path, &staged_data_path)
+            .map_err(|why| {
+                PatchConfigError::ClientFailed(format!(
+                    "Unable to repack archive {}: {}",
+                    repack.path, why
+                ))
+            })?;
+
+        let mut packed = BTreeMap::<String, String>::new();
+        packer
+            .read_entries(&staged_header_path, &staged_data_path, &mut |name, data| {
+                packed.insert(name.to_string(), fsobject::digest_of(data));
+                Ok(())
+            })
+            .map_err(|why| {
+                PatchConfigError::ClientFailed(format!(
+                    "Unable to read repacked archive {}: {}",
+                    repack.path, why
+                ))
+            })?;
+
+        if packed != repack.entries {
+            return Err(PatchConfigError::ClientFailed(format!(
+                "Repacked archive {} doesn't hold the entries of the patch",
+                repack.path
+            )));
+        }
+
+        if let Some(parent) = header_path.parent() {
+            create_dir(parent)?;
+        }
+        rename(&staged_header_path, &header_path)?;
+        rename(&staged_data_path, &data_path)?;
+
+        // The archive may have been installed unpacked before
+        let unpacked_path = install_path(install_dir, &repack.unpacked_path)?;
+        if unpacked_path.is_dir() {
+            remove_dir(&unpacked_path)?;
+        }
+
+        let _ = std::fs::remove_dir_all(&entries_dir);
+        for download in &repack.downloads {
+            let _ = std::fs::remove_file(downloads_dir.join(&download.object_digest));
+        }
+
+        Ok(())
+    }
+
+    /// Downloads an object, resuming a partial download if there is one, and
+    /// returns its contents once they match the file's digest. The partial
+    /// download is kept until the caller has installed the file.
+    fn fetch(
+        &self,
+        download: &PlannedDownload,
+        downloads_dir: &Path,
+    ) -> Result<Vec<u8>, PatchConfigError> {
+        let partial_path = downloads_dir.join(&download.object_digest);
+        self.download(download, &partial_path)?;
+
+        let mut data = std::fs::read(&partial_path).map_err(|why| {
+            PatchConfigError::ClientFailed(format!(
+                "Unable to read download {}: {}",
+                partial_path.to_string_lossy(),
+                why
+            ))
+        })?;
+
+        if let Some(codec) = download.codec {
+            data = codec.decompress(&data).map_err(|why| {
+                PatchConfigError::ClientFailed(format!(
+                    "Unable to decompress {}: {}",
+                    download.object, why
+                ))
+            })?;
+
+            if fsobject::digest_of(&data) != download.digest {
+                let _ = std::fs::remove_file(&partial_path);
+                return Err(PatchConfigError::ClientFailed(format!(
+                    "{} doesn't match its digest once decompressed",
+                    download.object
+                )));
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Downloads an object to a file, continuing a partial download if there
+    /// is one, and checks its digest
+    fn download(
+        &self,
+        download: &PlannedDownload,
+        partial_path: &Path,
+    ) -> Result<(), PatchConfigError> {
+        let offset = std::fs::metadata(partial_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        let OpenedFile { mut reader, start } = match self.source.open(&download.object, offset)? {
+            Some(x) => x,
+            None => {
+                return Err(PatchConfigError::ClientFailed(format!(
+                    "The output has no {}",
+                    download.object
+                )));
+            }
+        };
+
+        let mut partial = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(partial_path)
+            .and_then(|mut file| {
+                file.set_len(start)?;
+                file.seek(SeekFrom::Start(start))?;
+                Ok(file)
+            })
+            .map_err(|why| {
+                PatchConfigError::ClientFailed(format!(
+                    "Unable to write download {}: {}",
+                    partial_path.to_string_lossy(),
+                    why
+                ))
+            })?;
+
+        std::io::copy(&mut reader, &mut partial)
+            .and_then(|_| partial.flush())
+            .map_err(|why| {
+                PatchConfigError::ClientFailed(format!(
+                    "Unable to download {}: {}",
+                    download.object, why
+                ))
+            })?;
+
+        if installed_digest(partial_path)?.as_ref() != Some(&download.object_digest) {
+            let _ = std::fs::remove_file(partial_path);
+            return Err(PatchConfigError::ClientFailed(format!(
+                "{} doesn't match its digest",
+                download.object
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the unpacked archives of a patch tree, by their path without an
+/// extension
+fn collect_unpacked_archives<'a>(
+    dir: &'a Directory,
+    dir_path: &str,
+    archives: &mut BTreeMap<String, &'a Archive>,
+) {
+    for child in &dir.children {
+        match child {
+            FSObject::Directory(child_dir) => {
+                let child_path = diff::join(dir_path, &child_dir.name);
+                collect_unpacked_archives(child_dir, &child_path, archives);
+            }
+            FSObject::Archive(archive) => {
+                archives.insert(diff::join(dir_path, &archive.name), archive);
+            }
+            FSObject::File(_) | FSObject::PackedArchive(_) => {}
+        }
+    }
+}
+
+/// Resolves a path from the output's metadata within the install. The output
+/// may not be trusted, so every part of the path must be a plain name, which
+/// rules out parent directories, roots, drive prefixes and separators hidden
+/// inside names. The client's own download directory is also off limits.
+fn install_path(install_dir: &Path, path: &str) -> Result<PathBuf, PatchConfigError> {
+    let mut install_path = install_dir.to_path_buf();
+
+    for (i, segment) in path.split('/').enumerate() {
+        let mut components = Path::new(segment).components();
+        let plain = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(name)), None) if name == segment
+        );
+
+        if !plain
+            || segment.contains(['\\', ':', '\0'])
+            || (i == 0 && segment == DOWNLOADS_DIR_NAME)
+        {
+            return Err(PatchConfigError::ClientFailed(format!(
+                "The output names a path outside the install: {path:?}"
+            )));
+        }
+
+        install_path.push(segment);
+    }
+
+    Ok(install_path)
+}
+
+/// Percent-encodes each segment of a path of the output, so that names
+/// holding characters such as '#', '?', '%' and spaces survive in a URL
+fn encode_url_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+/// Decides which object to fetch for a file, preferring a compressed variant
+fn plan_download(
+    path: &str,
+    file: &File,
+    layout: OutputLayout,
+) -> Result<PlannedDownload, PatchConfigError> {
+    let (object, object_digest, codec) = match file.compressed.first() {
+        Some(variant) => (
+            format!(
+                "{}.{}",
+                object_path(path, file, layout),
+                variant.codec.extension()
+            ),
+            variant.digest.clone(),
+            Some(variant.codec),
+        ),
+        None => (object_path(path, file, layout), file.digest.clone(), None),
+    };
+
+    // Digests name files of the download directory
+    for digest in [&file.digest, &object_digest] {
+        if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(PatchConfigError::ClientFailed(format!(
+                "The output gives {path} an invalid digest: {digest:?}"
+            )));
+        }
+    }
+
+    Ok(PlannedDownload {
+        path: path.to_string(),
+        digest: file.digest.clone(),
+        object,
+        object_digest,
+        codec,
+        mtime: file.attributes.mtime,
+        executable: file.attributes.executable,
+    })
+}
+
+fn object_path(path: &str, file: &File, layout: OutputLayout) -> String {
+    match layout {
+        OutputLayout::Tree => format!("{PATCH_DIR_NAME}/{path}"),
+        OutputLayout::ContentAddressed => format!("{OBJECTS_DIR_NAME}/{}", file.digest),
+    }
+}
+
+/// Hashes a file of the install, if it exists
+fn installed_digest(path: &Path) -> Result<Option<String>, PatchConfigError> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let file = std::fs::File::open(path).map_err(|why| {
+        PatchConfigError::ClientFailed(format!(
+            "Unable to read {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    let file = File::from_reader("", BufReader::new(file)).map_err(|why| {
+        PatchConfigError::ClientFailed(format!(
+            "Unable to read {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    Ok(Some(file.digest))
+}
+
+fn create_dir(path: &Path) -> Result<(), PatchConfigError> {
+    std::fs::create_dir_all(path).map_err(|why| {
+        PatchConfigError::ClientFailed(format!(
+            "Unable to create directory {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })
+}
+
+fn remove_dir(path: &Path) -> Result<(), PatchConfigError> {
+    std::fs::remove_dir_all(path).map_err(|why| {
+        PatchConfigError::ClientFailed(format!(
+            "Unable to remove {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })
+}
+
+fn rename(from: &Path, to: &Path) -> Result<(), PatchConfigError> {
+    std::fs::rename(from, to).map_err(|why| {
+        PatchConfigError::ClientFailed(format!(
+            "Unable to install {}: {}",
+            to.to_string_lossy(),
+            why
+        ))
+    })
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), PatchConfigError> {
+    std::fs::write(path, data).map_err(|why| {
+        PatchConfigError::ClientFailed(format!(
+            "Unable to write {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })
+}
+
+fn set_attributes(path: &Path, download: &PlannedDownload) -> Result<(), PatchConfigError> {
+    let failed = |why: std::io::Error| {
+        PatchConfigError::ClientFailed(format!(
+            "Unable to set the attributes of {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    };
+
+    if let Some(mtime) = download.mtime {
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(modified))
+            .map_err(failed)?;
+    }
+
+    #[cfg(unix)]
+    if download.executable {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = std::fs::metadata(path).map_err(failed)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        std::fs::set_permissions(path, permissions).map_err(failed)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deletions::Tombstone, options::GenerateOptions};
+
+    /// Generates an output from files given by path, returning the temporary
+    /// directory which holds both, and the output's path
+    fn generate(
+        files: &[(&str, &[u8])],
+        options: &GenerateOptions,
+    ) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let source_dir = dir.path().join("source");
+        let output_dir = dir.path().join("output");
+
+        for (path, data) in files {
+            let path = source_dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+
+        crate::generate_config(&source_dir, &output_dir, options).unwrap();
+        (dir, output_dir)
+    }
+
+    fn client(output_dir: &Path, options: ClientOptions) -> PatchClient {
+        PatchClient::with_options(
+            PatchSource::Local(output_dir.to_path_buf()),
+            ArchiveExtensions::default(),
+            options,
+        )
+    }
+
+    /// Keeps an archive's entries as JSON in its HED, leaving its DAT empty
+    struct JsonPacker;
+
+    impl ArchivePacker for JsonPacker {
+        fn pack(&self, entries_dir: &Path, header_path: &Path, data_path: &Path) -> io::Result<()> {
+            let mut entries = BTreeMap::<String, Vec<u8>>::new();
+            for entry in std::fs::read_dir(entries_dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                entries.insert(name, std::fs::read(entry.path())?);
+            }
+
+            std::fs::write(header_path, serde_json::to_vec(&entries)?)?;
+            std::fs::write(data_path, [])
+        }
+
+        fn read_entries(
+            &self,
+            header_path: &Path,
+            _data_path: &Path,
+            visit: &mut dyn FnMut(&str, &[u8]) -> io::Result<()>,
+        ) -> io::Result<()> {
+            let entries: BTreeMap<String, Vec<u8>> =
+                serde_json::from_slice(&std::fs::read(header_path)?)?;
+            for (name, data) in &entries {
+                visit(name, data)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn installs_and_then_is_up_to_date() {
+        let (dir, output_dir) = generate(
+            &[("ECO.exe", b"game"), ("Data/items.csv", b"sword,shield")],
+            &GenerateOptions::default(),
+        );
+        let install_dir = dir.path().join("install");
+        let client = client(&output_dir, ClientOptions::default());
+
+        let release = client.fetch_release().unwrap();
+        let plan = client.plan(&release, &install_dir).unwrap();
+        assert_eq!(plan.downloads.len(), 2);
+        assert_eq!(plan.up_to_date, 0);
+
+        client.apply(&plan, &install_dir).unwrap();
+        assert_eq!(std::fs::read(install_dir.join("ECO.exe")).unwrap(), b"game");
+        assert_eq!(
+            std::fs::read(install_dir.join("Data/items.csv")).unwrap(),
+            b"sword,shield"
+        );
+        assert!(!install_dir.join(DOWNLOADS_DIR_NAME).exists());
+
+        let plan = client.plan(&release, &install_dir).unwrap();
+        assert!(plan.downloads.is_empty());
+        assert_eq!(plan.up_to_date, 2);
+    }
+
+    #[test]
+    fn uses_the_unpacked_extension_of_the_output() {
+        let mut options = GenerateOptions::default();
+        options.archive_extensions.unpacked = vec!["pak".to_string()];
+        let (dir, output_dir) = generate(&[("Data/music.pak/title.ogg", b"title")], &options);
+        let install_dir = dir.path().join("install");
+
+        // The client only knows the default extensions
+        let client = client(&output_dir, ClientOptions::default());
+
+        let release = client.fetch_release().unwrap();
+        let plan = client.plan(&release, &install_dir).unwrap();
+        client.apply(&plan, &install_dir).unwrap();
+        assert_eq!(
+            std::fs::read(install_dir.join("Data/music.pak/title.ogg")).unwrap(),
+            b"title"
+        );
+    }
+
+    #[test]
+    fn resumes_an_interrupted_download() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let (dir, output_dir) = generate(&[("Data/big.bin", &data)], &GenerateOptions::default());
+        let install_dir = dir.path().join("install");
+        let client = client(&output_dir, ClientOptions::default());
+
+        let release = client.fetch_release().unwrap();
+        let plan = client.plan(&release, &install_dir).unwrap();
+        let download = &plan.downloads[0];
+
+        // The first half was downloaded before the update was interrupted
+        let downloads_dir = install_dir.join(DOWNLOADS_DIR_NAME);
+        std::fs::create_dir_all(&downloads_dir).unwrap();
+        let partial_path = downloads_dir.join(&download.object_digest);
+        std::fs::write(&partial_path, &data[..5_000]).unwrap();
+
+        client.apply(&plan, &install_dir).unwrap();
+        assert_eq!(
+            std::fs::read(install_dir.join("Data/big.bin")).unwrap(),
+            data
+        );
+
+        // A partial download which is corrupt is resumed, fails its digest
+        // and is thrown away, so that the next attempt starts over
+        std::fs::remove_file(install_dir.join("Data/big.bin")).unwrap();
+        std::fs::create_dir_all(&downloads_dir).unwrap();
+        std::fs::write(&partial_path, vec![0u8; 5_000]).unwrap();
+
+        assert!(client.apply(&plan, &install_dir).is_err());
+        assert!(!partial_path.exists());
+
+        client.apply(&plan, &install_dir).unwrap();
+        assert_eq!(
+            std::fs::read(install_dir.join("Data/big.bin")).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_install() {
+        let (dir, output_dir) = generate(&[("ECO.exe", b"game")], &GenerateOptions::default());
+        let install_dir = dir.path().join("install");
+        let client = client(&output_dir, ClientOptions::default());
+
+        for name in ["..", ".", "a\\b", "C:", ""] {
+            let mut release = client.fetch_release().unwrap();
+            match &mut release.patchlist.children[0] {
+                FSObject::File(file) => file.name = name.to_string(),
+                _ => unreachable!(),
+            }
+            assert!(client.plan(&release, &install_dir).is_err(), "{name:?}");
+        }
+
+        for path in ["../outside", "/etc/passwd", "a//b", ".aeco-downloads/x"] {
+            let mut release = client.fetch_release().unwrap();
+            release.deletions.tombstones.push(Tombstone {
+                path: path.to_string(),
+                removed_in: "1".to_string(),
+            });
+            assert!(client.plan(&release, &install_dir).is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_metadata_without_a_valid_signature() {
+        let keys_dir = tempfile::tempdir().unwrap();
+        let secret_key_path = keys_dir.path().join("secret.key");
+        let public_key_path = keys_dir.path().join("public.key");
+        signing::generate_key_files(&secret_key_path, &public_key_path).unwrap();
+
+        let options = GenerateOptions {
+            signing_key: Some(secret_key_path),
+            ..GenerateOptions::default()
+        };
+        let (_dir, output_dir) = generate(&[("ECO.exe", b"game")], &options);
+        let public_key = signing::load_public_key(&public_key_path).unwrap();
+
+        let verifying_client = || {
+            client(
+                &output_dir,
+                ClientOptions {
+                    public_key: Some(public_key),
+                    ..ClientOptions::default()
+                },
+            )
+        };
+        verifying_client().fetch_release().unwrap();
+
+        let patchlist_path = output_dir.join(METADATA_DIR_NAME).join(PATCHLIST_FILE_NAME);
+        let mut patchlist = std::fs::read(&patchlist_path).unwrap();
+        patchlist.push(b' ');
+        std::fs::write(&patchlist_path, &patchlist).unwrap();
+
+        assert!(verifying_client().fetch_release().is_err());
+    }
+
+    #[test]
+    fn repacks_unpacked_archives() {
+        let (dir, output_dir) = generate(
+            &[
+                ("Data/music.archive/title.ogg", b"title"),
+                ("Data/music.archive/battle.ogg", b"battle"),
+            ],
+            &GenerateOptions::default(),
+        );
+        let install_dir = dir.path().join("install");
+
+        // An earlier client installed the archive unpacked
+        std::fs::create_dir_all(install_dir.join("Data/music.archive")).unwrap();
+        std::fs::write(install_dir.join("Data/music.archive/title.ogg"), b"old").unwrap();
+
+        let client = client(
+            &output_dir,
+            ClientOptions {
+                packer: Some(Box::new(JsonPacker)),
+                ..ClientOptions::default()
+            },
+        );
+
+        let release = client.fetch_release().unwrap();
+        let plan = client.plan(&release, &install_dir).unwrap();
+        assert!(plan.downloads.is_empty());
+        assert_eq!(plan.repacks.len(), 1);
+        assert_eq!(plan.repacks[0].path, "Data/music");
+        assert_eq!(plan.repacks[0].downloads.len(), 2);
+
+        client.apply(&plan, &install_dir).unwrap();
+        assert!(install_dir.join("Data/music.hed").is_file());
+        assert!(install_dir.join("Data/music.dat").is_file());
+        assert!(!install_dir.join("Data/music.archive").exists());
+
+        let plan = client.plan(&release, &install_dir).unwrap();
+        assert!(plan.repacks.is_empty());
+        assert_eq!(plan.up_to_date, 2);
+    }
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Compression formats which files can be precompressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+impl Codec {
+    /// The extension appended to the names of files compressed with the codec
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            Codec::Zstd => "zst",
+            Codec::Brotli => "br",
+        }
+    }
+
+    /// Compresses data as much as the codec reasonably allows. Files are only
+    /// compressed once per release, so ratio matters more than speed.
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Codec::Zstd => zstd::encode_all(data, 19),
+            Codec::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 9, 22);
+                    encoder.write_all(data)?;
+                }
+                Ok(compressed)
+            }
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Codec::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            }
+            Codec::Zstd => {
+                decompressed = zstd::decode_all(data)?;
+            }
+            Codec::Brotli => {
+                brotli::Decompressor::new(data, 4096).read_to_end(&mut decompressed)?;
+            }
+        }
+        Ok(decompressed)
+    }
+}
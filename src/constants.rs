@@ -1,3 +1,4 @@
 pub const UNPACKED_ARCHIVE_EXTENSION: &str = "archive";
 pub const ARCHIVE_DATA_EXTENSION: &str = "dat";
 pub const ARCHIVE_METADATA_EXTENSION: &str = "hed";
+pub const PACK_MARKER_FILE_NAME: &str = ".aeco-pack";
@@ -0,0 +1,310 @@
+use crate::{
+    error::PatchConfigError,
+    fsobject::{FileAttributes, Priority},
+    hash_cache::HashCache,
+    options::GenerateOptions,
+    output::OutputStats,
+    report::LayerOrigin,
+    scheduler::Scheduler,
+    sink::Sink,
+    source::{Source, SourceMetadata},
+};
+use glob::{MatchOptions, Pattern};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+/// Name of the directory which holds content-addressed objects
+pub const OBJECTS_DIR_NAME: &str = "objects";
+
+/// Prefix of the names objects are written under until they are complete
+const PARTIAL_OBJECT_PREFIX: &str = ".";
+
+/// Globs are matched the same way names are compared on Windows clients
+const GLOB_MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// State shared by every step of a single generation
+pub struct Context<'a> {
+    pub options: &'a GenerateOptions,
+    pub output_stats: OutputStats,
+    pub hash_cache: HashCache,
+    pub scheduler: Scheduler,
+    /// Where the source and its layers are read from
+    pub source: &'a dyn Source,
+    /// Where the output is written
+    pub sink: &'a dyn Sink,
+    /// The source followed by each of its layers
+    source_roots: Vec<PathBuf>,
+    target_root: PathBuf,
+    ignore: Vec<Pattern>,
+    packed_archives: Vec<Pattern>,
+    packs: Vec<(String, Vec<Pattern>)>,
+    executable: Vec<Pattern>,
+    background: Vec<Pattern>,
+    stored_objects: Mutex<HashSet<String>>,
+    /// Objects which were stored before this generation started
+    preexisting_objects: HashSet<String>,
+    origins: Mutex<BTreeMap<String, LayerOrigin>>,
+}
+
+impl<'a> Context<'a> {
+    /// Fails if the source or any of its layers isn't a directory
+    pub fn new(
+        options: &'a GenerateOptions,
+        source_root: &Path,
+        target_root: &Path,
+        source: &'a dyn Source,
+        sink: &'a dyn Sink,
+    ) -> Result<Self, PatchConfigError> {
+        let source_roots = source_roots(source_root, options);
+
+        for root in &source_roots {
+            if !source
+                .metadata(root)
+                .is_ok_and(|metadata| metadata.is_dir())
+            {
+                return Err(PatchConfigError::SourceNotDirectory(format!(
+                    "Source is not a directory: {}",
+                    root.to_string_lossy()
+                )));
+            }
+        }
+
+        let preexisting_objects = stored_objects(sink, &target_root.join(OBJECTS_DIR_NAME));
+
+        Ok(Self {
+            options,
+            output_stats: OutputStats::default(),
+            hash_cache: HashCache::load(options.hash_cache.as_deref()),
+            scheduler: Scheduler::new(&options.parallelism)?,
+            source,
+            sink,
+            source_roots,
+            target_root: target_root.to_path_buf(),
+            ignore: compile_patterns(&options.ignore)?,
+            packed_archives: compile_patterns(&options.packed_archives)?,
+            packs: options
+                .packs
+                .iter()
+                .map(|(pack, patterns)| {
+                    check_pack_name(pack)?;
+                    Ok((pack.clone(), compile_patterns(patterns)?))
+                })
+                .collect::<Result<_, PatchConfigError>>()?,
+            executable: compile_patterns(&options.attributes.executable)?,
+            background: compile_patterns(&options.attributes.background)?,
+            stored_objects: Mutex::new(preexisting_objects.clone()),
+            preexisting_objects,
+            origins: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// The source followed by each of its layers
+    pub fn source_roots(&self) -> &[PathBuf] {
+        &self.source_roots
+    }
+
+    /// The path of a source object relative to the source root or layer it
+    /// belongs to, with `/` as the separator on every platform
+    pub fn relative_path(&self, source_path: &Path) -> String {
+        // A layer could be inside another, so use the closest root
+        let relative = self
+            .source_roots
+            .iter()
+            .filter_map(|root| source_path.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.components().count())
+            .unwrap_or(source_path);
+
+        path_to_string(relative)
+    }
+
+    /// The pack a source file, directory or archive is put in by the pack
+    /// globs, if any
+    pub fn pack_of(&self, source_path: &Path) -> Option<&str> {
+        let relative_path = self.relative_path(source_path);
+
+        self.packs
+            .iter()
+            .find(|(_, patterns)| matches_any(patterns, &relative_path))
+            .map(|(pack, _)| pack.as_str())
+    }
+
+    /// Records which layer a file written to the patch directory came from,
+    /// replacing anything recorded for the same path before
+    pub fn record_origin(&self, target_path: &Path, origin: LayerOrigin) {
+        let relative = self.patch_relative_path(target_path);
+
+        if let Ok(mut origins) = self.origins.lock() {
+            origins.insert(relative, origin);
+        }
+    }
+
+    /// The path of a target in the patch directory relative to the patch
+    /// root, with `/` as the separator on every platform
+    fn patch_relative_path(&self, target_path: &Path) -> String {
+        let patch_root = self.target_root.join(crate::PATCH_DIR_NAME);
+        path_to_string(target_path.strip_prefix(&patch_root).unwrap_or(target_path))
+    }
+
+    /// Decides the attributes of a file written to the given target path.
+    /// Source metadata is only available for files which exist on their own
+    /// in the source, not for archive entries.
+    pub fn file_attributes(
+        &self,
+        target_path: &Path,
+        source_metadata: Option<&SourceMetadata>,
+    ) -> FileAttributes {
+        let relative_path = self.patch_relative_path(target_path);
+
+        let mtime = match source_metadata {
+            Some(metadata) if self.options.attributes.record_mtime => metadata
+                .modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+            _ => None,
+        };
+
+        let executable = matches_any(&self.executable, &relative_path)
+            || source_metadata.is_some_and(|metadata| metadata.executable);
+
+        let priority = if matches_any(&self.background, &relative_path) {
+            Priority::Background
+        } else {
+            Priority::Startup
+        };
+
+        FileAttributes {
+            mtime,
+            executable,
+            priority,
+        }
+    }
+
+    /// Takes the origins recorded so far
+    pub fn take_origins(&self) -> BTreeMap<String, LayerOrigin> {
+        match self.origins.lock() {
+            Ok(mut origins) => std::mem::take(&mut *origins),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+
+    /// Where the object with the given name, which is its digest along with
+    /// any compression extension, is stored in the content-addressed layout
+    pub fn object_path(&self, object_name: &str) -> PathBuf {
+        self.target_root.join(OBJECTS_DIR_NAME).join(object_name)
+    }
+
+    /// Marks an object as stored. Returns false if it had already been
+    /// claimed, or was stored by an earlier generation, in which case the
+    /// caller doesn't need to write it again.
+    pub fn claim_object(&self, object_name: &str) -> bool {
+        match self.stored_objects.lock() {
+            Ok(mut stored_objects) => stored_objects.insert(object_name.to_string()),
+            // Writing an object twice is harmless
+            Err(_) => true,
+        }
+    }
+
+    /// Removes the objects this generation stored, once it has failed. They
+    /// are complete, but nothing refers to them.
+    pub fn discard_new_objects(&self) {
+        let new_objects = match self.stored_objects.lock() {
+            Ok(stored_objects) => stored_objects
+                .difference(&self.preexisting_objects)
+                .cloned()
+                .collect::<Vec<String>>(),
+            Err(_) => return,
+        };
+
+        for object_name in new_objects {
+            let _ = self.sink.remove_file(&self.object_path(&object_name));
+        }
+    }
+
+    /// If a source file or directory should be left out of the patch
+    pub fn is_ignored(&self, source_path: &Path) -> bool {
+        matches_any(&self.ignore, &self.relative_path(source_path))
+    }
+
+    /// If the archive with the given HED should be copied to the output as-is
+    /// rather than unpacked
+    pub fn keep_packed(&self, source_hed_path: &Path) -> bool {
+        matches_any(&self.packed_archives, &self.relative_path(source_hed_path))
+    }
+}
+
+fn path_to_string(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Names of the objects already in an object directory. Release channels
+/// share one object directory, so objects written for another channel are
+/// reused. Objects are renamed into place once they are complete, so any
+/// partial object left by an interrupted generation is skipped.
+fn stored_objects(sink: &dyn Sink, objects_dir: &Path) -> HashSet<String> {
+    match sink.list(objects_dir) {
+        Ok(names) => names
+            .into_iter()
+            .filter(|name| !name.starts_with(PARTIAL_OBJECT_PREFIX))
+            .collect(),
+        Err(_) => HashSet::new(),
+    }
+}
+
+/// The name an object is written under until it is complete. The process id
+/// keeps generations which share the object directory apart.
+pub fn partial_object_path(object_path: &Path) -> PathBuf {
+    let mut name = OsString::from(PARTIAL_OBJECT_PREFIX);
+    if let Some(file_name) = object_path.file_name() {
+        name.push(file_name);
+    }
+    name.push(format!(".{}", std::process::id()));
+
+    object_path.with_file_name(name)
+}
+
+/// The source root followed by each of its layers
+pub fn source_roots(source_root: &Path, options: &GenerateOptions) -> Vec<PathBuf> {
+    std::iter::once(source_root.to_path_buf())
+        .chain(options.layers.iter().cloned())
+        .collect()
+}
+
+/// Pack names are shown to players and used as keys in the pack index
+pub fn check_pack_name(pack: &str) -> Result<(), PatchConfigError> {
+    if pack.trim().is_empty() || pack.trim() != pack {
+        return Err(PatchConfigError::InvalidPack(format!(
+            "Invalid pack name {pack:?}, pack names may not be empty or start or end with spaces"
+        )));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn compile_patterns(patterns: &[String]) -> Result<Vec<Pattern>, PatchConfigError> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern).map_err(|why| {
+                PatchConfigError::InvalidPattern(format!("Invalid glob {pattern}: {why}"))
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn matches_any(patterns: &[Pattern], relative_path: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_with(relative_path, GLOB_MATCH_OPTIONS))
+}
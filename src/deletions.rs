@@ -0,0 +1,132 @@
+use crate::{
+    context::{compile_patterns, matches_any},
+    error::PatchConfigError,
+};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+/// The deletion list written to meta/deletions.json, which tells launchers
+/// which local files to remove
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Deletions {
+    /// Globs, relative to the install directory, of user-owned paths such as
+    /// screenshots and settings. Launchers must never delete these, even when
+    /// cleaning up files the patch doesn't know about. A glob which matches a
+    /// directory protects everything in it.
+    pub protected: Vec<String>,
+    /// Files which earlier releases installed and the current one doesn't
+    pub tombstones: Vec<Tombstone>,
+}
+
+/// A file, or an entry of an unpacked archive, which has been removed from
+/// the patch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Path relative to the install directory, with `/` as the separator
+    pub path: String,
+    /// Id of the release which removed it
+    pub removed_in: String,
+}
+
+/// Reads the deletion list of an earlier release. Releases generated before
+/// deletion lists were written have none.
+pub fn load_deletions(meta_dir: &Path) -> Result<Deletions, PatchConfigError> {
+    let path = meta_dir.join(crate::DELETIONS_FILE_NAME);
+
+    if !path.exists() {
+        return Ok(Deletions::default());
+    }
+
+    let text = std::fs::read_to_string(&path).map_err(|why| {
+        PatchConfigError::ReleaseFailed(format!(
+            "Unable to read metadata {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    serde_json::from_str(&text).map_err(|why| {
+        PatchConfigError::ReleaseFailed(format!(
+            "Metadata {} is invalid: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })
+}
+
+/// Builds the deletion list of a new release. Tombstones of earlier releases
+/// are kept, so that launchers which skipped releases still remove every
+/// stale file, and files removed since the previous release are added.
+/// Files are given as flattened trees, see [`crate::diff::flatten`].
+pub fn update_deletions(
+    previous: Deletions,
+    previous_files: &BTreeMap<String, String>,
+    current_files: &BTreeMap<String, String>,
+    release_id: &str,
+    protected: &[String],
+) -> Result<Deletions, PatchConfigError> {
+    let protected_patterns = compile_patterns(protected)?;
+
+    // Windows clients compare paths case-insensitively, so a tombstone must
+    // not match a current file with different casing, nor a directory which
+    // now holds files
+    let mut current_paths = HashSet::<String>::new();
+    for path in current_files.keys() {
+        let path = path.to_lowercase();
+        let mut prefix = path.as_str();
+        while let Some((parent, _)) = prefix.rsplit_once('/') {
+            current_paths.insert(parent.to_string());
+            prefix = parent;
+        }
+        current_paths.insert(path);
+    }
+
+    let is_current = |path: &str| current_paths.contains(&path.to_lowercase());
+
+    let mut tombstones = previous
+        .tombstones
+        .into_iter()
+        .filter(|tombstone| !is_current(&tombstone.path))
+        .collect::<Vec<Tombstone>>();
+
+    let known = tombstones
+        .iter()
+        .map(|tombstone| tombstone.path.to_lowercase())
+        .collect::<HashSet<String>>();
+
+    for path in previous_files.keys() {
+        if !is_current(path) && !known.contains(&path.to_lowercase()) {
+            tombstones.push(Tombstone {
+                path: path.clone(),
+                removed_in: release_id.to_string(),
+            });
+        }
+    }
+
+    tombstones.retain(|tombstone| !is_protected(&protected_patterns, &tombstone.path));
+    tombstones.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(Deletions {
+        protected: protected.to_vec(),
+        tombstones,
+    })
+}
+
+/// If a path, or any directory it is in, matches a protected glob
+fn is_protected(protected_patterns: &[Pattern], path: &str) -> bool {
+    let mut prefix = path;
+    loop {
+        if matches_any(protected_patterns, prefix) {
+            return true;
+        }
+
+        match prefix.rsplit_once('/') {
+            Some((parent, _)) => prefix = parent,
+            None => return false,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{fsobject::*, options::ArchiveExtensions};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Lists every file a client installs from a patch tree, keyed by its path
+/// relative to the root with `/` as the separator, and valued by its digest
+pub fn flatten(root: &Directory, extensions: &ArchiveExtensions) -> BTreeMap<String, String> {
+    flatten_files(root, extensions)
+        .into_iter()
+        .map(|(path, file)| (path, file.digest.clone()))
+        .collect()
+}
+
+/// Like [`flatten`], but valued by the files themselves
+pub fn flatten_files<'a>(
+    root: &'a Directory,
+    extensions: &ArchiveExtensions,
+) -> BTreeMap<String, &'a File> {
+    let mut files = BTreeMap::<String, &File>::new();
+    flatten_directory(root, "", extensions, &mut files);
+    files
+}
+
+fn flatten_directory<'a>(
+    dir: &'a Directory,
+    dir_path: &str,
+    extensions: &ArchiveExtensions,
+    files: &mut BTreeMap<String, &'a File>,
+) {
+    for child in &dir.children {
+        match child {
+            FSObject::File(file) => {
+                files.insert(join(dir_path, &file.name), file);
+            }
+            FSObject::Directory(child_dir) => {
+                let child_path = join(dir_path, &child_dir.name);
+                flatten_directory(child_dir, &child_path, extensions, files);
+            }
+            FSObject::Archive(archive) => {
+                let archive_path = join(
+                    dir_path,
+                    &format!("{}.{}", archive.name, extensions.unpacked_output()),
+                );
+                for file in &archive.files {
+                    files.insert(join(&archive_path, &file.name), file);
+                }
+            }
+            FSObject::PackedArchive(archive) => {
+                // Clients install the HED and DAT, not the entries inside them
+                for file in [&archive.header, &archive.data] {
+                    files.insert(join(dir_path, &file.name), file);
+                }
+            }
+        }
+    }
+}
+
+/// Appends a name to a path relative to the patch root
+pub(crate) fn join(dir_path: &str, name: &str) -> String {
+    if dir_path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir_path}/{name}")
+    }
+}
+
+/// The files which differ between two patch trees
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChangeSummary {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ChangeSummary {
+    /// Compares two flattened trees, see [`flatten`]
+    pub fn between(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Self {
+        let mut summary = ChangeSummary::default();
+
+        for (path, digest) in new {
+            match old.get(path) {
+                None => summary.added.push(path.clone()),
+                Some(old_digest) if old_digest != digest => summary.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+
+        summary.removed = old
+            .keys()
+            .filter(|path| !new.contains_key(*path))
+            .cloned()
+            .collect();
+
+        summary
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
@@ -15,6 +15,23 @@ pub enum PatchConfigError {
     MetadataDirectoryFailed(String),
     WriteMetadataFailed(String),
     ArchiveContainsDirectory(String),
+    IncompatibleFileName(String),
+    ArchiveConflict(String),
+    InvalidPattern(String),
+    LinkTargetFileFailed(String),
+    CompressFailed(String),
+    KeyFailed(String),
+    SignatureInvalid(String),
+    ProjectFailed(String),
+    ReleaseFailed(String),
+    ChannelFailed(String),
+    LayerConflict(String),
+    InvalidPack(String),
+    LauncherFailed(String),
+    ServeFailed(String),
+    ClientFailed(String),
+    BudgetExceeded(String),
+    ThreadPoolFailed(String),
 }
 
 impl ToString for PatchConfigError {
@@ -35,6 +52,23 @@ impl ToString for PatchConfigError {
             PatchConfigError::MetadataDirectoryFailed(s) => s,
             PatchConfigError::WriteMetadataFailed(s) => s,
             PatchConfigError::ArchiveContainsDirectory(s) => s,
+            PatchConfigError::IncompatibleFileName(s) => s,
+            PatchConfigError::ArchiveConflict(s) => s,
+            PatchConfigError::InvalidPattern(s) => s,
+            PatchConfigError::LinkTargetFileFailed(s) => s,
+            PatchConfigError::CompressFailed(s) => s,
+            PatchConfigError::KeyFailed(s) => s,
+            PatchConfigError::SignatureInvalid(s) => s,
+            PatchConfigError::ProjectFailed(s) => s,
+            PatchConfigError::ReleaseFailed(s) => s,
+            PatchConfigError::ChannelFailed(s) => s,
+            PatchConfigError::LayerConflict(s) => s,
+            PatchConfigError::InvalidPack(s) => s,
+            PatchConfigError::LauncherFailed(s) => s,
+            PatchConfigError::ServeFailed(s) => s,
+            PatchConfigError::ClientFailed(s) => s,
+            PatchConfigError::BudgetExceeded(s) => s,
+            PatchConfigError::ThreadPoolFailed(s) => s,
         }
         .clone()
     }
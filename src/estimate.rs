@@ -0,0 +1,120 @@
+use crate::{
+    diff,
+    error::PatchConfigError,
+    fsobject::{Directory, File},
+    options::ArchiveExtensions,
+    release,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+/// How much a player on an earlier release downloads to reach the current one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateEstimate {
+    /// Release the player starts from
+    pub from: String,
+    /// Number of files to download
+    pub files: u64,
+    /// Bytes to download, counting compressed variants where a client would
+    /// download them instead
+    pub bytes: u64,
+    /// The largest downloads, largest first
+    pub largest: Vec<EstimatedDownload>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EstimatedDownload {
+    /// Path relative to the install directory
+    pub path: String,
+    pub bytes: u64,
+}
+
+/// Estimates the update from one patch tree to another. Files are compared
+/// by digest, so renaming a file downloads it again. Manifests written before
+/// sizes were recorded count as 0 bytes.
+pub fn estimate_update(
+    from: &str,
+    previous: &Directory,
+    current: &Directory,
+    extensions: &ArchiveExtensions,
+    largest: usize,
+) -> UpdateEstimate {
+    let previous_files = diff::flatten(previous, extensions);
+    estimate_from_files(from, &previous_files, current, extensions, largest)
+}
+
+/// Like [`estimate_update`], but with the earlier tree already flattened, see
+/// [`diff::flatten`]
+pub(crate) fn estimate_from_files(
+    from: &str,
+    previous_files: &BTreeMap<String, String>,
+    current: &Directory,
+    extensions: &ArchiveExtensions,
+    largest: usize,
+) -> UpdateEstimate {
+    let mut downloads = diff::flatten_files(current, extensions)
+        .into_iter()
+        .filter(|(path, file)| previous_files.get(path) != Some(&file.digest))
+        .map(|(path, file)| EstimatedDownload {
+            path,
+            bytes: download_size(file),
+        })
+        .collect::<Vec<EstimatedDownload>>();
+
+    downloads.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+
+    let files = downloads.len() as u64;
+    let bytes = downloads.iter().map(|download| download.bytes).sum();
+    downloads.truncate(largest);
+
+    UpdateEstimate {
+        from: from.to_string(),
+        files,
+        bytes,
+        largest: downloads,
+    }
+}
+
+/// Estimates the update to the release in an output from each of a number of
+/// earlier outputs. Each estimate is labelled with the earlier release's id,
+/// or its path if it has no release.json.
+pub fn estimate_updates<P>(
+    current_output: P,
+    previous_outputs: &[P],
+    extensions: &ArchiveExtensions,
+    largest: usize,
+) -> Result<Vec<UpdateEstimate>, PatchConfigError>
+where
+    P: AsRef<Path>,
+{
+    let meta_dir = |output: &P| output.as_ref().join(crate::METADATA_DIR_NAME);
+
+    let current = release::load_patchlist(&meta_dir(&current_output))?;
+
+    previous_outputs
+        .iter()
+        .map(|previous_output| {
+            let previous_meta_dir = meta_dir(previous_output);
+            let previous = release::load_patchlist(&previous_meta_dir)?;
+
+            let from = if previous_meta_dir.join(crate::RELEASE_FILE_NAME).exists() {
+                release::load_release(&previous_meta_dir)?.id
+            } else {
+                previous_output.as_ref().to_string_lossy().to_string()
+            };
+
+            Ok(estimate_update(
+                &from, &previous, &current, extensions, largest,
+            ))
+        })
+        .collect()
+}
+
+/// The size a client downloads for a file, which is its first compressed
+/// variant if it has one
+fn download_size(file: &File) -> u64 {
+    match file.compressed.first() {
+        Some(variant) => variant.size,
+        None => file.size,
+    }
+}
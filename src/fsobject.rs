@@ -1,27 +1,140 @@
+use crate::compression::Codec;
 use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Formats the digest which identifies a file's contents
+pub fn digest_of(data: &[u8]) -> String {
+    format!("{:32x}", md5::compute(data))
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FSObject {
     File(File),
     Directory(Directory),
     Archive(Archive),
+    PackedArchive(PackedArchive),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct File {
     pub name: String,
     pub digest: String,
+    /// Size of the file in bytes. Manifests written before sizes were
+    /// recorded have 0.
+    #[serde(default)]
+    pub size: u64,
+    /// Precompressed copies of the file which clients may download instead
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compressed: Vec<CompressedVariant>,
+    /// The optional content pack this belongs to. Files without a pack are
+    /// required.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack: Option<String>,
+    #[serde(default, skip_serializing_if = "FileAttributes::is_default")]
+    pub attributes: FileAttributes,
+}
+
+/// What launchers need to know about a file besides its contents
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileAttributes {
+    /// When the source file was last modified, in seconds since the Unix
+    /// epoch, so launchers can restore it. Archive entries have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// If the file is an executable or library, which can't be replaced while
+    /// the game is running and requires a restart when it is updated
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub executable: bool,
+    #[serde(default, skip_serializing_if = "Priority::is_startup")]
+    pub priority: Priority,
+}
+
+impl FileAttributes {
+    pub fn is_default(&self) -> bool {
+        *self == FileAttributes::default()
+    }
+}
+
+/// When a launcher has to download a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Priority {
+    /// The file must be installed before the game can start
+    #[default]
+    Startup,
+    /// The file may be downloaded in the background while the game runs
+    Background,
+}
+
+impl Priority {
+    fn is_startup(&self) -> bool {
+        *self == Priority::Startup
+    }
+}
+
+/// A compressed copy of a file, stored next to it with the codec's extension
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompressedVariant {
+    pub codec: Codec,
+    /// Size of the compressed data
+    pub size: u64,
+    /// Digest of the compressed data, so it can be checked before it is
+    /// decompressed
+    pub digest: String,
 }
 
 impl File {
     pub fn new(name: &str, data: &[u8]) -> Self {
-        let digest = md5::compute(&data);
-        let digest = format!("{:32x}", digest);
+        Self {
+            name: name.to_string(),
+            digest: digest_of(data),
+            size: data.len() as u64,
+            compressed: Vec::new(),
+            pack: None,
+            attributes: FileAttributes::default(),
+        }
+    }
 
+    /// Describes a file whose digest is already known
+    pub fn from_digest(name: &str, digest: String, size: u64) -> Self {
         Self {
             name: name.to_string(),
             digest,
+            size,
+            compressed: Vec::new(),
+            pack: None,
+            attributes: FileAttributes::default(),
+        }
+    }
+
+    /// Hashes data from a reader without holding all of it in memory. The
+    /// number of bytes read is the file's size.
+    pub fn from_reader<R>(name: &str, mut reader: R) -> std::io::Result<Self>
+    where
+        R: Read,
+    {
+        let mut hasher = md5::Context::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut size = 0u64;
+
+        loop {
+            let count = reader.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            hasher.consume(&buffer[..count]);
+            size += count as u64;
         }
+
+        let digest = format!("{:32x}", hasher.compute());
+
+        Ok(Self {
+            name: name.to_string(),
+            digest,
+            size,
+            compressed: Vec::new(),
+            pack: None,
+            attributes: FileAttributes::default(),
+        })
     }
 }
 
@@ -29,10 +142,32 @@ impl File {
 pub struct Directory {
     pub name: String,
     pub children: Vec<FSObject>,
+    /// The optional content pack this belongs to. Everything without a pack
+    /// is required.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Archive {
     pub name: String,
     pub files: Vec<File>,
+    /// The optional content pack this belongs to. Everything without a pack
+    /// is required.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack: Option<String>,
+}
+
+/// An archive which is published as its original HED and DAT rather than as
+/// loose files
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PackedArchive {
+    pub name: String,
+    pub header: File,
+    pub data: File,
+    pub files: Vec<File>,
+    /// The optional content pack this belongs to. Everything without a pack
+    /// is required.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack: Option<String>,
 }
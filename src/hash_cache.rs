@@ -0,0 +1,245 @@
+use crate::{
+    error::PatchConfigError,
+    fsobject::File,
+    source::{DiskSource, Source, SourceMetadata},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+/// Bumped whenever the format or the digests change, so that older caches
+/// are ignored rather than trusted
+const CACHE_VERSION: u32 = 1;
+
+/// Digests of source files and archive entries from earlier generations, so
+/// that unchanged sources aren't hashed again. Entries are only trusted while
+/// the source's size, modification time and inode are unchanged.
+///
+/// Entries which are looked up or added during a generation are saved, and
+/// everything else is dropped, so the cache doesn't grow without bound.
+pub struct HashCache {
+    path: Option<PathBuf>,
+    previous: CacheData,
+    current: Mutex<CacheData>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheData {
+    version: u32,
+    files: HashMap<String, CachedFile>,
+    archives: HashMap<String, CachedArchive>,
+}
+
+/// What identifies a version of a source file without reading it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Fingerprint {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    inode: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedFile {
+    fingerprint: Fingerprint,
+    digest: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedArchive {
+    header: Fingerprint,
+    data: Fingerprint,
+    /// In the order the archive lists them
+    entries: Vec<CachedEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedEntry {
+    name: String,
+    digest: String,
+    size: u64,
+}
+
+impl HashCache {
+    /// Loads the cache at a path. A missing, unreadable or corrupt cache is
+    /// treated as empty, so everything is hashed again. Without a path,
+    /// nothing is cached.
+    pub fn load(path: Option<&Path>) -> Self {
+        let previous = path
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|data| serde_json::from_slice::<CacheData>(&data).ok())
+            .filter(|data| data.version == CACHE_VERSION)
+            .unwrap_or_default();
+
+        Self {
+            path: path.map(Path::to_path_buf),
+            previous,
+            current: Mutex::new(CacheData {
+                version: CACHE_VERSION,
+                ..CacheData::default()
+            }),
+        }
+    }
+
+    /// The cached digest of a source file, if it hasn't changed since it was
+    /// hashed
+    pub fn file(&self, name: &str, source_path: &Path, metadata: &SourceMetadata) -> Option<File> {
+        self.path.as_ref()?;
+
+        let key = cache_key(source_path);
+        let cached = self.previous.files.get(&key)?;
+        if cached.fingerprint != fingerprint(metadata)? {
+            return None;
+        }
+
+        if let Ok(mut current) = self.current.lock() {
+            current.files.insert(key, cached.clone());
+        }
+
+        Some(File::from_digest(name, cached.digest.clone(), metadata.len))
+    }
+
+    /// Records the digest of a source file which was just hashed
+    pub fn insert_file(&self, source_path: &Path, metadata: &SourceMetadata, file: &File) {
+        if self.path.is_none() {
+            return;
+        }
+
+        let fingerprint = match fingerprint(metadata) {
+            Some(x) => x,
+            None => return,
+        };
+
+        if let Ok(mut current) = self.current.lock() {
+            current.files.insert(
+                cache_key(source_path),
+                CachedFile {
+                    fingerprint,
+                    digest: file.digest.clone(),
+                },
+            );
+        }
+    }
+
+    /// The cached entries of an archive, if neither its HED nor its DAT has
+    /// changed since they were hashed
+    pub fn archive(&self, hed_path: &Path, dat_path: &Path) -> Option<Vec<File>> {
+        self.path.as_ref()?;
+
+        let key = cache_key(hed_path);
+        let cached = self.previous.archives.get(&key)?;
+        if cached.header != path_fingerprint(hed_path)?
+            || cached.data != path_fingerprint(dat_path)?
+        {
+            return None;
+        }
+
+        if let Ok(mut current) = self.current.lock() {
+            current.archives.insert(key, cached.clone());
+        }
+
+        Some(
+            cached
+                .entries
+                .iter()
+                .map(|entry| File::from_digest(&entry.name, entry.digest.clone(), entry.size))
+                .collect(),
+        )
+    }
+
+    /// Records the digests of every entry of an archive which was just hashed
+    pub fn insert_archive(&self, hed_path: &Path, dat_path: &Path, entries: &[File]) {
+        if self.path.is_none() {
+            return;
+        }
+
+        let (header, data) = match (path_fingerprint(hed_path), path_fingerprint(dat_path)) {
+            (Some(header), Some(data)) => (header, data),
+            _ => return,
+        };
+
+        let entries = entries
+            .iter()
+            .map(|file| CachedEntry {
+                name: file.name.clone(),
+                digest: file.digest.clone(),
+                size: file.size,
+            })
+            .collect();
+
+        if let Ok(mut current) = self.current.lock() {
+            current.archives.insert(
+                cache_key(hed_path),
+                CachedArchive {
+                    header,
+                    data,
+                    entries,
+                },
+            );
+        }
+    }
+
+    /// Writes the cache back to its path. It is written to a temporary file
+    /// first, so a concurrent generation never reads half a cache.
+    pub fn save(&self) -> Result<(), PatchConfigError> {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let save_error = |why: String| {
+            PatchConfigError::WriteTargetFileFailed(format!(
+                "Unable to save hash cache {}: {}",
+                path.to_string_lossy(),
+                why
+            ))
+        };
+
+        let json = match self.current.lock() {
+            Ok(current) => {
+                serde_json::to_vec(&*current).map_err(|why| save_error(why.to_string()))?
+            }
+            Err(_) => return Err(save_error("it was poisoned".to_string())),
+        };
+
+        let mut temporary_name = path.as_os_str().to_os_string();
+        temporary_name.push(format!(".{}.tmp", std::process::id()));
+        let temporary_path = PathBuf::from(temporary_name);
+
+        std::fs::write(&temporary_path, json)
+            .and_then(|_| std::fs::rename(&temporary_path, path))
+            .map_err(|why| {
+                let _ = std::fs::remove_file(&temporary_path);
+                save_error(why.to_string())
+            })
+    }
+}
+
+/// Sources are keyed by absolute path, so the cache works from any working
+/// directory
+fn cache_key(source_path: &Path) -> String {
+    let source_path = std::path::absolute(source_path).unwrap_or(source_path.to_path_buf());
+    source_path.to_string_lossy().to_string()
+}
+
+/// Archives are only cached while both halves are on disk
+fn path_fingerprint(path: &Path) -> Option<Fingerprint> {
+    fingerprint(&DiskSource.metadata(path).ok()?)
+}
+
+/// Files without an inode, such as those in packaged builds, have no
+/// fingerprint which can be trusted
+fn fingerprint(metadata: &SourceMetadata) -> Option<Fingerprint> {
+    let mtime = metadata.modified?.duration_since(UNIX_EPOCH).ok()?;
+
+    Some(Fingerprint {
+        size: metadata.len,
+        mtime_secs: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos(),
+        inode: metadata.inode?,
+    })
+}
@@ -0,0 +1,125 @@
+use crate::{
+    error::PatchConfigError, fsobject::digest_of, options::LauncherOptions, signing, sink::Sink,
+};
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Directory of the output which holds published launcher binaries
+pub const LAUNCHER_DIR_NAME: &str = "launcher";
+
+/// The launcher section of the metadata, written to meta/launcher.json.
+/// Launchers check it, and update themselves, before reading the patch list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LauncherEntry {
+    /// Path of the binary relative to the output root, such as
+    /// launcher/2.1.0/launcher.exe
+    pub path: String,
+    pub version: String,
+    pub size: u64,
+    pub digest: String,
+    /// Signature of the binary, when the metadata is signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Checks the launcher options before any output is written
+pub fn check_launcher(launcher: &LauncherOptions) -> Result<(), PatchConfigError> {
+    if !launcher.path.is_file() {
+        return Err(PatchConfigError::LauncherFailed(format!(
+            "Launcher is not a file: {}",
+            launcher.path.to_string_lossy()
+        )));
+    }
+
+    // The version becomes a directory name
+    let version = launcher.version.as_str();
+    let valid = !version.is_empty()
+        && version != "."
+        && version != ".."
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '+');
+
+    if !valid {
+        return Err(PatchConfigError::LauncherFailed(format!(
+            "Invalid launcher version {version:?}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Copies the launcher binary to launcher/<version>/ in the output and
+/// describes it. Release channels share the launcher directory, so a version
+/// which is already published is kept, as long as it is identical.
+pub fn publish_launcher(
+    sink: &dyn Sink,
+    launcher: &LauncherOptions,
+    target_dir: &Path,
+    signing_key: Option<&SigningKey>,
+) -> Result<LauncherEntry, PatchConfigError> {
+    let file_name = match launcher.path.file_name() {
+        Some(x) => x.to_string_lossy().to_string(),
+        None => {
+            return Err(PatchConfigError::LauncherFailed(format!(
+                "The launcher at {} has an invalid name",
+                launcher.path.to_string_lossy()
+            )));
+        }
+    };
+
+    let data = std::fs::read(&launcher.path).map_err(|why| {
+        PatchConfigError::ReadSourceFileFailed(format!(
+            "Failed to read file {}: {}",
+            launcher.path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    let digest = digest_of(&data);
+
+    let version_dir = target_dir.join(LAUNCHER_DIR_NAME).join(&launcher.version);
+    let target_path = version_dir.join(&file_name);
+
+    if sink.exists(&target_path) {
+        let existing = sink.read(&target_path).map_err(|why| {
+            PatchConfigError::LauncherFailed(format!(
+                "Failed to read published launcher {}: {}",
+                target_path.to_string_lossy(),
+                why
+            ))
+        })?;
+
+        if digest_of(&existing) != digest {
+            return Err(PatchConfigError::LauncherFailed(format!(
+                "Launcher version {} is already published with different contents",
+                launcher.version
+            )));
+        }
+    } else {
+        sink.create_dir_all(&version_dir).map_err(|why| {
+            PatchConfigError::CreateTargetDirectoryFailed(format!(
+                "Unable to create target directory {}: {}",
+                version_dir.to_string_lossy(),
+                why
+            ))
+        })?;
+
+        sink.write(&target_path, &data).map_err(|why| {
+            PatchConfigError::WriteTargetFileFailed(format!(
+                "Failed to write file {}: {}",
+                target_path.to_string_lossy(),
+                why
+            ))
+        })?;
+    }
+
+    Ok(LauncherEntry {
+        path: format!("{LAUNCHER_DIR_NAME}/{}/{file_name}", launcher.version),
+        version: launcher.version.clone(),
+        size: data.len() as u64,
+        digest,
+        signature: signing_key.map(|signing_key| signing::sign(signing_key, &data)),
+    })
+}
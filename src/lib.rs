@@ -1,45 +1,117 @@
-use std::path::{Path, PathBuf};
+use ed25519_dalek::SigningKey;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
+pub mod budget;
+pub mod channel;
+pub mod client;
+pub mod compression;
+pub mod deletions;
+pub mod diff;
 pub mod error;
+pub mod estimate;
+use context::Context;
 use error::PatchConfigError;
+use options::{GenerateOptions, OutputLayout, PublishedLayout};
+use report::GenerationReport;
+use sink::{DiskSink, Sink};
+use source::{Source, Sources};
 
 pub mod fsobject;
+pub mod launcher;
+pub mod options;
+pub mod packs;
+pub mod project;
+pub mod release;
+pub mod report;
+pub mod signing;
+pub mod sink;
+pub mod source;
 pub mod status;
+pub mod validate;
 
 mod constants;
+mod context;
+mod hash_cache;
+mod output;
 mod process_archive;
 mod process_directory;
 mod process_file;
-use process_directory::process_dir;
+mod scheduler;
+use process_directory::{check_root_pack_markers, plan_tree, process_planned_dir, LayerDir};
 
 const PATCH_DIR_NAME: &str = "patch";
 const METADATA_DIR_NAME: &str = "meta";
+const PATCHLIST_FILE_NAME: &str = "patchlist.json";
+const STATUS_FILE_NAME: &str = "status.json";
+const LAYOUT_FILE_NAME: &str = "layout.json";
+const PACKS_FILE_NAME: &str = "packs.json";
+const DELETIONS_FILE_NAME: &str = "deletions.json";
+const LAUNCHER_FILE_NAME: &str = "launcher.json";
+const RELEASE_FILE_NAME: &str = "release.json";
+const RELEASE_HISTORY_FILE_NAME: &str = "releases.json";
 
 pub fn generate_config<P>(
     source_dir: P,
     target_dir: P,
-    maintenance: bool,
-) -> Result<(), PatchConfigError>
+    options: &GenerateOptions,
+) -> Result<GenerationReport, PatchConfigError>
 where
     P: AsRef<Path>,
 {
-    // The source must already exist and must be a directory
-    if !source_dir.as_ref().is_dir() {
-        return Err(PatchConfigError::SourceNotDirectory(format!(
-            "Source is not a directory: {}",
-            source_dir.as_ref().to_string_lossy()
-        )));
-    }
+    check_source_dirs(source_dir.as_ref(), options)?;
 
+    let sources = Sources::open(&context::source_roots(source_dir.as_ref(), options))?;
+
+    generate_config_with(source_dir, target_dir, options, &sources, &DiskSink)
+}
+
+/// Like [`generate_config`], but reads the source from and writes the output
+/// to the given backends, either of which may be somewhere other than disk,
+/// such as [`source::MemorySource`] and [`sink::MemorySink`]. The source and
+/// target directories are paths within them. The previous output, signing key
+/// and launcher binary are still read from disk.
+pub fn generate_config_with<P>(
+    source_dir: P,
+    target_dir: P,
+    options: &GenerateOptions,
+    source: &dyn Source,
+    sink: &dyn Sink,
+) -> Result<GenerationReport, PatchConfigError>
+where
+    P: AsRef<Path>,
+{
     // The target directory shouldn't exist yet
-    if target_dir.as_ref().exists() {
+    if sink.exists(target_dir.as_ref()) {
         return Err(PatchConfigError::TargetAlreadyExists(format!(
             "Target already exists: {}",
             target_dir.as_ref().to_string_lossy()
         )));
     }
 
-    if let Err(why) = std::fs::create_dir(&target_dir) {
+    let previous_meta_dir = options
+        .previous_output
+        .as_ref()
+        .map(|previous_output| previous_output.join(METADATA_DIR_NAME));
+
+    let context = Context::new(
+        options,
+        source_dir.as_ref(),
+        target_dir.as_ref(),
+        source,
+        sink,
+    )?;
+    let prepared = prepare_release(
+        previous_meta_dir.as_deref(),
+        Some(target_dir.as_ref()),
+        options,
+    )?;
+
+    if let Err(why) = sink.create_dir(target_dir.as_ref()) {
         return Err(PatchConfigError::CreateTargetDirectoryFailed(format!(
             "Unable to create target directory {}: {}",
             target_dir.as_ref().to_string_lossy(),
@@ -47,37 +119,201 @@ where
         )));
     }
 
+    let result = generate_release(
+        target_dir.as_ref(),
+        &target_dir.as_ref().join(METADATA_DIR_NAME),
+        prepared,
+        &context,
+    );
+
+    // Nothing in a failed output can be published, such as a patch which is
+    // over budget
+    if result.is_err() {
+        let _ = sink.remove_dir_all(target_dir.as_ref());
+    }
+
+    result
+}
+
+/// The source and each of its layers must already exist and must be
+/// directories, or packaged builds which are checked once they are opened
+fn check_source_dirs(source_dir: &Path, options: &GenerateOptions) -> Result<(), PatchConfigError> {
+    for dir in std::iter::once(source_dir).chain(options.layers.iter().map(PathBuf::as_path)) {
+        if !dir.is_dir() && source::package_path(dir).is_none() {
+            return Err(PatchConfigError::SourceNotDirectory(format!(
+                "Source is not a directory: {}",
+                dir.to_string_lossy()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Everything a release needs from outside the source, loaded before any
+/// output is written so that bad keys or histories fail fast
+struct PreparedRelease {
+    signing_key: Option<SigningKey>,
+    release_id: String,
+    history: Vec<release::HistoryEntry>,
+    /// If there is a previous release to compare with
+    has_previous: bool,
+    /// Problems found while preparing which don't stop the generation
+    warnings: Vec<String>,
+    /// The files of the previous release, see [`diff::flatten`]
+    previous_files: BTreeMap<String, String>,
+    previous_deletions: deletions::Deletions,
+}
+
+/// Without a previous output, earlier outputs next to the target directory, if
+/// one is given, are warned about, since their history isn't continued.
+fn prepare_release(
+    previous_meta_dir: Option<&Path>,
+    target_dir: Option<&Path>,
+    options: &GenerateOptions,
+) -> Result<PreparedRelease, PatchConfigError> {
+    let signing_key = match &options.signing_key {
+        Some(path) => Some(signing::load_signing_key(path)?),
+        None => None,
+    };
+
+    let (history, previous_files, previous_deletions) = match previous_meta_dir {
+        Some(previous_meta_dir) => (
+            release::load_history(previous_meta_dir)?,
+            diff::flatten(
+                &release::load_patchlist(previous_meta_dir)?,
+                &options.archive_extensions,
+            ),
+            deletions::load_deletions(previous_meta_dir)?,
+        ),
+        None => (Vec::new(), BTreeMap::new(), deletions::Deletions::default()),
+    };
+
+    let release_id = release::next_release_id(options.release.as_deref(), &history)?;
+
+    // Without a previous output the history starts over, which is rarely
+    // intended when earlier outputs sit next to the target
+    let mut warnings = Vec::new();
+    if let (None, Some(target_dir)) = (previous_meta_dir, target_dir) {
+        if let Some(sibling) = release::find_sibling_output(target_dir) {
+            warnings.push(format!(
+                "No previous output was given, so the release history starts over at release {release_id}, although {} has a history",
+                sibling.to_string_lossy()
+            ));
+        }
+    }
+
+    // The protected globs and launcher are only used at the end, so check
+    // them now
+    context::compile_patterns(&options.protected)?;
+    if let Some(launcher) = &options.launcher {
+        launcher::check_launcher(launcher)?;
+    }
+
+    Ok(PreparedRelease {
+        signing_key,
+        has_previous: previous_meta_dir.is_some(),
+        warnings,
+        release_id,
+        history,
+        previous_files,
+        previous_deletions,
+    })
+}
+
+/// Generates a release from the source and its layers into the target
+/// directory, writing its metadata into a new metadata directory. Storage
+/// shared with earlier releases in the target directory is reused.
+fn generate_release(
+    target_dir: &Path,
+    metadata_dir: &Path,
+    prepared: PreparedRelease,
+    context: &Context,
+) -> Result<GenerationReport, PatchConfigError> {
+    let options = context.options;
+    let started = Instant::now();
+
+    let PreparedRelease {
+        signing_key,
+        release_id,
+        mut history,
+        has_previous,
+        mut warnings,
+        previous_files,
+        previous_deletions,
+    } = prepared;
+
+    let layer_dirs = context
+        .source_roots()
+        .iter()
+        .enumerate()
+        .map(|(layer, path)| LayerDir {
+            layer,
+            path: path.clone(),
+        })
+        .collect::<Vec<LayerDir>>();
+
+    check_root_pack_markers(&layer_dirs, context)?;
+
+    // Refuse to publish names that Windows clients would be unable to install,
+    // before anything is written for them
+    let plan = context
+        .scheduler
+        .install(|| plan_tree(&layer_dirs, context))?;
+    let windows_limits = validate::WindowsLimits::new(options.windows_install_prefix);
+    check_windows_names(plan.windows_name_problems(windows_limits, context))?;
+
     let patch_dir_name = PATCH_DIR_NAME;
     let mut patch_dir = PathBuf::new();
     patch_dir.push(&target_dir);
     patch_dir.push(patch_dir_name);
 
-    if let Err(why) = std::fs::create_dir(&patch_dir) {
+    // Content-addressed output stores every file in a flat object directory
+    // rather than in a copy of the source tree
+    let storage_dir = match options.layout {
+        OutputLayout::Tree => patch_dir.clone(),
+        OutputLayout::ContentAddressed => target_dir.join(context::OBJECTS_DIR_NAME),
+    };
+
+    // Channels share the object directory, so it may already exist
+    if let Err(why) = context.sink.create_dir_all(&storage_dir) {
         return Err(PatchConfigError::CreateTargetDirectoryFailed(format!(
             "Unable to create target directory {}: {}",
-            patch_dir.to_string_lossy(),
+            storage_dir.to_string_lossy(),
             why
         )));
     }
 
-    let dir_obj = process_dir(&source_dir.as_ref(), &patch_dir.as_ref(), patch_dir_name)?;
+    let dir_obj = context.scheduler.install(|| {
+        process_planned_dir(plan, patch_dir.as_path(), patch_dir_name, None, context)
+    })?;
 
-    let patch_json = match serde_json::to_string(&dir_obj) {
-        Ok(x) => x,
-        Err(why) => {
-            return Err(PatchConfigError::MetadataFailed(format!(
-                "Failed to serialize metadata: {}",
-                why
+    // The entries of archives are only known now that they have been read
+    check_windows_names(validate::windows_name_problems(
+        &dir_obj,
+        &options.archive_extensions,
+        windows_limits,
+    ))?;
+
+    // Refuse to publish releases far larger than intended, unless the budget
+    // only warns
+    let problems = budget::budget_problems(
+        &dir_obj,
+        has_previous.then_some(&previous_files),
+        &options.budget,
+        &options.archive_extensions,
+    );
+    if !problems.is_empty() {
+        if !options.budget.soft {
+            return Err(PatchConfigError::BudgetExceeded(format!(
+                "The patch exceeds its budget:\n{}",
+                problems.join("\n")
             )));
         }
-    };
-
-    let metadata_dir_name = METADATA_DIR_NAME;
-    let mut metadata_dir = PathBuf::new();
-    metadata_dir.push(&target_dir);
-    metadata_dir.push(metadata_dir_name);
+        warnings.extend(problems);
+    }
 
-    if let Err(why) = std::fs::create_dir(&metadata_dir) {
+    if let Err(why) = context.sink.create_dir(metadata_dir) {
         return Err(PatchConfigError::MetadataDirectoryFailed(format!(
             "Unable to create metadata directory {}: {}",
             metadata_dir.to_string_lossy(),
@@ -85,25 +321,161 @@ where
         )));
     }
 
-    let mut patch_data_path = PathBuf::new();
-    patch_data_path.push(&metadata_dir);
-    patch_data_path.push("patchlist.json");
+    let signing_key = signing_key.as_ref();
 
-    if let Err(why) = std::fs::write(&patch_data_path, &patch_json) {
-        return Err(PatchConfigError::WriteMetadataFailed(format!(
-            "Unable to write metadata file {}: {}",
-            patch_data_path.to_string_lossy(),
-            why
-        )));
-    }
+    let patchlist = write_metadata(
+        context.sink,
+        metadata_dir,
+        PATCHLIST_FILE_NAME,
+        &dir_obj,
+        signing_key,
+    )?;
 
-    let server_status = if maintenance {
+    let server_status = if options.maintenance {
         status::ServerStatus::Maintenance
     } else {
         status::ServerStatus::Online
     };
 
-    let status_json = match serde_json::to_string(&server_status) {
+    write_metadata(
+        context.sink,
+        metadata_dir,
+        STATUS_FILE_NAME,
+        &server_status,
+        signing_key,
+    )?;
+
+    // Clients need to know whether to fetch files by path or by digest
+    write_metadata(
+        context.sink,
+        metadata_dir,
+        LAYOUT_FILE_NAME,
+        &PublishedLayout {
+            layout: options.layout,
+            unpacked_extension: Some(options.archive_extensions.unpacked_output().to_string()),
+        },
+        signing_key,
+    )?;
+
+    // The launcher updates itself before it reads anything else, so it isn't
+    // part of the patch tree
+    if let Some(launcher) = &options.launcher {
+        let entry = launcher::publish_launcher(context.sink, launcher, target_dir, signing_key)?;
+        write_metadata(
+            context.sink,
+            metadata_dir,
+            LAUNCHER_FILE_NAME,
+            &entry,
+            signing_key,
+        )?;
+    }
+
+    // Launchers offer these packs to players, and skip the ones not chosen
+    let packs = packs::pack_index(&dir_obj, &options.archive_extensions);
+    write_metadata(
+        context.sink,
+        metadata_dir,
+        PACKS_FILE_NAME,
+        &packs,
+        signing_key,
+    )?;
+
+    let current_files = diff::flatten(&dir_obj, &options.archive_extensions);
+    let contents = report::ContentSummary::of(&dir_obj, &options.archive_extensions);
+
+    let release = release::Release {
+        id: release_id,
+        root_digest: fsobject::digest_of(patchlist.as_bytes()),
+        timestamp: release::timestamp(),
+        changes: diff::ChangeSummary::between(&previous_files, &current_files),
+    };
+
+    // Launchers can't tell a removed file from user data, so list removals
+    let deletions = deletions::update_deletions(
+        previous_deletions,
+        &previous_files,
+        &current_files,
+        &release.id,
+        &options.protected,
+    )?;
+    write_metadata(
+        context.sink,
+        metadata_dir,
+        DELETIONS_FILE_NAME,
+        &deletions,
+        signing_key,
+    )?;
+
+    write_metadata(
+        context.sink,
+        metadata_dir,
+        RELEASE_FILE_NAME,
+        &release,
+        signing_key,
+    )?;
+
+    // The history is only ever appended to, so older releases stay listed
+    let release_id = release.id.clone();
+    let changes = has_previous.then(|| release.changes.clone());
+    history.push(release::HistoryEntry::from(&release));
+    write_metadata(
+        context.sink,
+        metadata_dir,
+        RELEASE_HISTORY_FILE_NAME,
+        &history,
+        signing_key,
+    )?;
+
+    // A cache which can't be saved only makes the next generation slower
+    if let Err(why) = context.hash_cache.save() {
+        warnings.push(why.to_string());
+    }
+
+    // println!("{obj:?}");
+
+    Ok(GenerationReport {
+        release: release_id,
+        written_files: context.output_stats.written_files(),
+        written_bytes: context.output_stats.written_bytes(),
+        deduplicated_files: context.output_stats.deduplicated_files(),
+        deduplicated_bytes: context.output_stats.deduplicated_bytes(),
+        linked_files: context.output_stats.linked_files(),
+        layers: context.source_roots().to_vec(),
+        origins: context.take_origins(),
+        warnings,
+        contents,
+        changes,
+        duration_seconds: started.elapsed().as_secs_f64(),
+    })
+}
+
+/// Fails when there are names which Windows clients can't install, see
+/// [`validate::windows_name_problems`]
+fn check_windows_names(problems: Vec<String>) -> Result<(), PatchConfigError> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    Err(PatchConfigError::IncompatibleFileName(format!(
+        "Source contains names which are incompatible with Windows:\n{}",
+        problems.join("\n")
+    )))
+}
+
+/// Serializes a document into the metadata directory. If a signing key is
+/// given, a detached signature is written next to it. Returns the JSON which
+/// was written.
+fn write_metadata<T>(
+    sink: &dyn Sink,
+    metadata_dir: &Path,
+    file_name: &str,
+    value: &T,
+    signing_key: Option<&SigningKey>,
+) -> Result<String, PatchConfigError>
+where
+    T: Serialize,
+{
+    let json = match serde_json::to_string(value) {
         Ok(x) => x,
         Err(why) => {
             return Err(PatchConfigError::MetadataFailed(format!(
@@ -113,19 +485,152 @@ where
         }
     };
 
-    let mut status_data_path = PathBuf::new();
-    status_data_path.push(&metadata_dir);
-    status_data_path.push("status.json");
+    let mut data_path = PathBuf::new();
+    data_path.push(metadata_dir);
+    data_path.push(file_name);
 
-    if let Err(why) = std::fs::write(&status_data_path, &status_json) {
+    if let Err(why) = sink.write(&data_path, json.as_bytes()) {
         return Err(PatchConfigError::WriteMetadataFailed(format!(
             "Unable to write metadata file {}: {}",
-            patch_data_path.to_string_lossy(),
+            data_path.to_string_lossy(),
             why
         )));
     }
 
-    // println!("{obj:?}");
+    if let Some(signing_key) = signing_key {
+        signing::write_signature(sink, signing_key, &data_path, json.as_bytes())?;
+    }
 
-    Ok(())
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fsobject::{digest_of, Directory, FSObject};
+    use sink::MemorySink;
+    use source::MemorySource;
+
+    fn child<'a>(dir: &'a Directory, name: &str) -> &'a FSObject {
+        dir.children
+            .iter()
+            .find(|child| match child {
+                FSObject::File(file) => file.name == name,
+                FSObject::Directory(dir) => dir.name == name,
+                FSObject::Archive(archive) => archive.name == name,
+                FSObject::PackedArchive(archive) => archive.name == name,
+            })
+            .unwrap_or_else(|| panic!("{name} is missing from the patchlist"))
+    }
+
+    #[test]
+    fn generates_into_memory() {
+        let mut source = MemorySource::new();
+        source
+            .add_file("eco/ECO.exe", "base")
+            .add_file("eco/Data/items.csv", "sword,shield")
+            .add_file("eco/Data/music.archive/title.ogg", "title")
+            .add_file("layer/ECO.exe", "patched");
+
+        let options = GenerateOptions {
+            layers: vec![PathBuf::from("layer")],
+            ..GenerateOptions::default()
+        };
+        let sink = MemorySink::new();
+
+        generate_config_with("eco", "out", &options, &source, &sink).unwrap();
+
+        let files = sink.files();
+        let patchlist: Directory = serde_json::from_slice(
+            &files[&Path::new("out")
+                .join(METADATA_DIR_NAME)
+                .join(PATCHLIST_FILE_NAME)],
+        )
+        .unwrap();
+
+        // The layer's file replaces the source's
+        match child(&patchlist, "ECO.exe") {
+            FSObject::File(file) => assert_eq!(file.digest, digest_of(b"patched")),
+            other => panic!("ECO.exe is not a file: {other:?}"),
+        }
+        assert_eq!(
+            files[&Path::new("out").join(PATCH_DIR_NAME).join("ECO.exe")],
+            b"patched"
+        );
+
+        let data = match child(&patchlist, "Data") {
+            FSObject::Directory(dir) => dir,
+            other => panic!("Data is not a directory: {other:?}"),
+        };
+
+        match child(data, "items.csv") {
+            FSObject::File(file) => assert_eq!(file.digest, digest_of(b"sword,shield")),
+            other => panic!("items.csv is not a file: {other:?}"),
+        }
+
+        match child(data, "music") {
+            FSObject::Archive(archive) => {
+                assert_eq!(archive.files.len(), 1);
+                assert_eq!(archive.files[0].name, "title.ogg");
+                assert_eq!(archive.files[0].digest, digest_of(b"title"));
+            }
+            other => panic!("music is not an unpacked archive: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn hardlinked_archive_overrides_leave_sources_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("eco");
+        let layer = dir.path().join("layer");
+        let target = dir.path().join("out");
+
+        for (root, contents) in [(&source, "base"), (&layer, "patched")] {
+            let archive_dir = root.join("Data").join("music.archive");
+            std::fs::create_dir_all(&archive_dir).unwrap();
+            std::fs::write(archive_dir.join("title.ogg"), contents).unwrap();
+        }
+        let source_archive_dir = source.join("Data").join("music.archive");
+        std::fs::write(source_archive_dir.join("menu.ogg"), "menu").unwrap();
+
+        let options = GenerateOptions {
+            layers: vec![layer.clone()],
+            link_mode: options::LinkMode::Hardlink,
+            ..GenerateOptions::default()
+        };
+
+        generate_config(&source, &target, &options).unwrap();
+
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        let archive_dir = target
+            .join(PATCH_DIR_NAME)
+            .join("Data")
+            .join("music.archive");
+        assert_eq!(read(archive_dir.join("title.ogg")), "patched");
+        assert_eq!(read(archive_dir.join("menu.ogg")), "menu");
+        assert_eq!(read(source_archive_dir.join("title.ogg")), "base");
+        assert_eq!(read(layer.join("Data/music.archive/title.ogg")), "patched");
+    }
+
+    #[test]
+    fn budgets_only_count_published_archive_entries() {
+        let mut source = MemorySource::new();
+        source
+            .add_file("eco/Data/music.archive/title.ogg", "x".repeat(500))
+            .add_file("layer/Data/music.archive/title.ogg", "title");
+
+        let options = GenerateOptions {
+            layers: vec![PathBuf::from("layer")],
+            budget: options::BudgetOptions {
+                max_file_bytes: Some(100),
+                max_total_bytes: Some(100),
+                ..options::BudgetOptions::default()
+            },
+            ..GenerateOptions::default()
+        };
+        let sink = MemorySink::new();
+
+        // The oversized entry is overridden, so it is never published
+        generate_config_with("eco", "out", &options, &source, &sink).unwrap();
+    }
 }
@@ -0,0 +1,354 @@
+use crate::{compression::Codec, constants::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+/// Settings which control how a patch configuration is generated. These can
+/// also be loaded from a project file, see [`crate::project`].
+//
+// Fields which are tables in TOML must come after plain values, or the
+// options can't be saved as TOML.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerateOptions {
+    /// If the server should be in maintenance mode
+    pub maintenance: bool,
+    /// Directories which are laid over the source in order, such as a
+    /// localization and then a hotfix. Files in later layers override files
+    /// with the same path in earlier ones, and archive entries are overridden
+    /// individually. Like the source, a layer may be a packaged build.
+    pub layers: Vec<PathBuf>,
+    /// Globs, relative to the source directory, of files and directories
+    /// which are left out of the patch
+    pub ignore: Vec<String>,
+    /// Globs, relative to the install directory, of user-owned paths such as
+    /// screenshots and settings, which launchers must never delete. A glob
+    /// which matches a directory protects everything in it.
+    pub protected: Vec<String>,
+    pub archive_conflicts: ArchiveConflictPolicy,
+    /// Globs, relative to the source directory, of HEDs whose archives should
+    /// be copied to the output packed instead of being unpacked
+    pub packed_archives: Vec<String>,
+    pub layout: OutputLayout,
+    pub link_mode: LinkMode,
+    /// Secret key used to sign the metadata. When unset, no signatures are
+    /// written.
+    pub signing_key: Option<PathBuf>,
+    /// Identifier of the release, either a semantic version or a build number.
+    /// When unset, the build number after the previous release is used.
+    pub release: Option<String>,
+    /// Output of the previous release. Its release history is carried
+    /// forward, and changes are summarized against its patch list.
+    pub previous_output: Option<PathBuf>,
+    /// File in which digests of source files are kept between generations,
+    /// so that unchanged files aren't hashed again. When unset, everything is
+    /// hashed.
+    pub hash_cache: Option<PathBuf>,
+    /// Characters of the Windows path limit to leave for the directory
+    /// clients install the game in. When unset,
+    /// [`crate::validate::DEFAULT_WINDOWS_INSTALL_PREFIX`] are left.
+    pub windows_install_prefix: Option<usize>,
+    pub archive_extensions: ArchiveExtensions,
+    pub compression: CompressionOptions,
+    /// Optional content packs, by name, along with globs of the files,
+    /// directories and archives in them. Globs are relative to the source
+    /// directory. A directory other than the root of the source or a layer
+    /// may also be put in a pack by placing a `.aeco-pack` file holding the
+    /// pack's name in it.
+    pub packs: BTreeMap<String, Vec<String>>,
+    pub attributes: AttributeRules,
+    /// The launcher binary to publish for self-updates, if any
+    pub launcher: Option<LauncherOptions>,
+    pub budget: BudgetOptions,
+    pub parallelism: ParallelismOptions,
+}
+
+/// How the work of a generation is spread over threads. By default every
+/// core is used and disk access isn't limited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParallelismOptions {
+    /// Number of worker threads. When unset, one per core.
+    pub threads: Option<usize>,
+    /// Process everything on the calling thread, one file at a time, which
+    /// makes failures easier to follow
+    pub sequential: bool,
+    /// Most source files or archive entries read at once. Limiting reads
+    /// helps on spinning disks and network shares, where many concurrent
+    /// reads are slower than a few.
+    pub max_concurrent_reads: Option<usize>,
+    /// Most output files written at once
+    pub max_concurrent_writes: Option<usize>,
+    /// Start the largest files and archives of each directory first, so a
+    /// large file isn't left to finish alone at the end. Ordering is per
+    /// directory: subdirectories are started before any file next to them,
+    /// and files in different directories aren't ordered against each other.
+    pub largest_first: bool,
+}
+
+/// Size limits which catch releases that are far larger than intended, such
+/// as when an exporter re-encodes every asset. Limits which are unset aren't
+/// checked.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BudgetOptions {
+    /// Most bytes a player on the previous release may download to update.
+    /// Only checked when there is a previous release.
+    pub max_delta_bytes: Option<u64>,
+    /// Largest size of any single file
+    pub max_file_bytes: Option<u64>,
+    /// Largest size of every file in the patch together
+    pub max_total_bytes: Option<u64>,
+    /// Warn about exceeded limits in the report instead of failing
+    pub soft: bool,
+}
+
+/// Where to find the launcher binary, which is published outside the patch
+/// tree since it is running while it patches
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LauncherOptions {
+    pub path: PathBuf,
+    /// Version of the launcher, which launchers compare against their own
+    pub version: String,
+}
+
+/// Rules which decide the attributes of files. Globs are relative to the
+/// patch root, so archive entries are matched as `<name>.archive/<entry>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttributeRules {
+    /// If source modification times should be recorded
+    pub record_mtime: bool,
+    /// Globs of executables and libraries, which require a restart when they
+    /// are updated. Files which are executable on the filesystem are
+    /// included as well.
+    pub executable: Vec<String>,
+    /// Globs of files which may be downloaded in the background while the
+    /// game runs. Everything else must be installed before it starts.
+    pub background: Vec<String>,
+}
+
+impl Default for AttributeRules {
+    fn default() -> Self {
+        Self {
+            record_mtime: true,
+            executable: vec!["**/*.exe".to_string(), "**/*.dll".to_string()],
+            background: Vec::new(),
+        }
+    }
+}
+
+/// Controls which precompressed variants are written next to each file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionOptions {
+    /// Codecs to write a compressed variant with. When empty, nothing is
+    /// compressed.
+    pub codecs: Vec<Codec>,
+    /// If the uncompressed file should be written as well as its compressed
+    /// variants. Files which aren't compressed are always written.
+    pub keep_uncompressed: bool,
+    /// Extensions of files which are already compressed, and which would
+    /// gain nothing from being compressed again
+    pub skip_extensions: Vec<String>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            codecs: Vec::new(),
+            keep_uncompressed: true,
+            skip_extensions: [
+                "gz", "zst", "br", "zip", "7z", "rar", "jpg", "jpeg", "png", "ogg", "mp3",
+            ]
+            .iter()
+            .map(|extension| extension.to_string())
+            .collect(),
+        }
+    }
+}
+
+impl CompressionOptions {
+    /// If a file with the given name should get compressed variants
+    pub fn applies_to(&self, file_name: &str) -> bool {
+        if self.codecs.is_empty() {
+            return false;
+        }
+
+        match Path::new(file_name).extension() {
+            Some(extension) => !matches_any(extension, &self.skip_extensions),
+            None => true,
+        }
+    }
+}
+
+/// How regular source files are placed in the output. Archive entries are
+/// always written, since they don't exist as files in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum LinkMode {
+    /// Read each file and write a copy of it. Files which get compressed
+    /// variants are always copied, since they are read in full anyway.
+    #[default]
+    Copy,
+    /// Hardlink files into the output. The output and source must be on the
+    /// same filesystem, and later edits to the source will show up in the
+    /// output.
+    Hardlink,
+    /// Share file data with the source where the filesystem supports it, and
+    /// fall back to copying otherwise
+    ReflinkOrCopy,
+}
+
+/// How files are arranged in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OutputLayout {
+    /// The patch directory mirrors the source directory
+    #[default]
+    Tree,
+    /// Every distinct file is stored once as objects/<digest>, and clients
+    /// use the manifest to map paths to digests
+    ContentAddressed,
+}
+
+/// How an output is arranged, as recorded in its layout.json
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "LayoutFile")]
+pub struct PublishedLayout {
+    pub layout: OutputLayout,
+    /// The extension of the directories archives were unpacked into, see
+    /// [`ArchiveExtensions::unpacked_output`]. Outputs generated before it was
+    /// recorded have none.
+    pub unpacked_extension: Option<String>,
+}
+
+impl PublishedLayout {
+    /// The archive extensions to find the output's files with, based on the
+    /// given ones
+    pub fn archive_extensions(&self, extensions: &ArchiveExtensions) -> ArchiveExtensions {
+        let mut extensions = extensions.clone();
+        if let Some(unpacked) = &self.unpacked_extension {
+            extensions
+                .unpacked
+                .retain(|extension| extension != unpacked);
+            extensions.unpacked.insert(0, unpacked.clone());
+        }
+        extensions
+    }
+}
+
+/// Outputs generated before the unpacked extension was recorded hold only the
+/// layout
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LayoutFile {
+    Layout(OutputLayout),
+    Published {
+        layout: OutputLayout,
+        #[serde(default)]
+        unpacked_extension: Option<String>,
+    },
+}
+
+impl From<LayoutFile> for PublishedLayout {
+    fn from(file: LayoutFile) -> Self {
+        match file {
+            LayoutFile::Layout(layout) => PublishedLayout {
+                layout,
+                unpacked_extension: None,
+            },
+            LayoutFile::Published {
+                layout,
+                unpacked_extension,
+            } => PublishedLayout {
+                layout,
+                unpacked_extension,
+            },
+        }
+    }
+}
+
+/// What to do when a packed archive and an unpacked archive folder in the same
+/// directory have the same name, such as sound.hed and sound.archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ArchiveConflictPolicy {
+    /// Refuse to generate the configuration
+    #[default]
+    Error,
+    /// Unpack the packed archive, then let files in the unpacked folder
+    /// override its entries
+    MergeUnpacked,
+}
+
+/// The file extensions used to recognize ECO archives. Extensions are
+/// compared case-insensitively, so "hed" also matches "SOUND.HED".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveExtensions {
+    /// Extensions of the half of a packed archive which holds file contents
+    pub data: Vec<String>,
+    /// Extensions of the half of a packed archive which holds its index
+    pub metadata: Vec<String>,
+    /// Extensions of directories which hold an unpacked archive. The first one
+    /// is also used when unpacking archives into the output.
+    pub unpacked: Vec<String>,
+}
+
+impl Default for ArchiveExtensions {
+    fn default() -> Self {
+        Self {
+            data: vec![ARCHIVE_DATA_EXTENSION.to_string()],
+            metadata: vec![ARCHIVE_METADATA_EXTENSION.to_string()],
+            unpacked: vec![UNPACKED_ARCHIVE_EXTENSION.to_string()],
+        }
+    }
+}
+
+impl ArchiveExtensions {
+    pub fn is_data(&self, extension: &OsStr) -> bool {
+        matches_any(extension, &self.data)
+    }
+
+    pub fn is_metadata(&self, extension: &OsStr) -> bool {
+        matches_any(extension, &self.metadata)
+    }
+
+    pub fn is_unpacked(&self, extension: &OsStr) -> bool {
+        matches_any(extension, &self.unpacked)
+    }
+
+    /// The extension given to directories that archives are unpacked into
+    pub fn unpacked_output(&self) -> &str {
+        match self.unpacked.first() {
+            Some(extension) => extension,
+            None => UNPACKED_ARCHIVE_EXTENSION,
+        }
+    }
+
+    /// The extension given to the index half of archives that are packed
+    pub fn metadata_output(&self) -> &str {
+        match self.metadata.first() {
+            Some(extension) => extension,
+            None => ARCHIVE_METADATA_EXTENSION,
+        }
+    }
+
+    /// The extension given to the contents half of archives that are packed
+    pub fn data_output(&self) -> &str {
+        match self.data.first() {
+            Some(extension) => extension,
+            None => ARCHIVE_DATA_EXTENSION,
+        }
+    }
+}
+
+fn matches_any(extension: &OsStr, candidates: &[String]) -> bool {
+    match extension.to_str() {
+        Some(extension) => candidates
+            .iter()
+            .any(|candidate| candidate.eq_ignore_ascii_case(extension)),
+        None => false,
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{
+    budget,
+    context::{self, Context},
+    error::PatchConfigError,
+    fsobject::{digest_of, CompressedVariant, File},
+    options::OutputLayout,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Running totals of what has been written to the output
+#[derive(Default)]
+pub struct OutputStats {
+    written_files: AtomicU64,
+    written_bytes: AtomicU64,
+    deduplicated_files: AtomicU64,
+    deduplicated_bytes: AtomicU64,
+    linked_files: AtomicU64,
+    published_bytes: AtomicU64,
+}
+
+impl OutputStats {
+    fn record_written(&self, bytes: u64) {
+        self.written_files.fetch_add(1, Ordering::Relaxed);
+        self.written_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn record_deduplicated(&self, bytes: u64) {
+        self.deduplicated_files.fetch_add(1, Ordering::Relaxed);
+        self.deduplicated_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn record_linked(&self) {
+        self.linked_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds a file to the size of the patch tree, returning the new size
+    pub(crate) fn record_published(&self, bytes: u64) -> u64 {
+        self.published_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes
+    }
+
+    pub fn written_files(&self) -> u64 {
+        self.written_files.load(Ordering::Relaxed)
+    }
+
+    pub fn written_bytes(&self) -> u64 {
+        self.written_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn deduplicated_files(&self) -> u64 {
+        self.deduplicated_files.load(Ordering::Relaxed)
+    }
+
+    pub fn deduplicated_bytes(&self) -> u64 {
+        self.deduplicated_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn linked_files(&self) -> u64 {
+        self.linked_files.load(Ordering::Relaxed)
+    }
+}
+
+/// Creates a directory of the patch tree. Content-addressed output has no
+/// directories besides the object store, so nothing is created for it.
+pub fn create_output_dir(context: &Context, target_path: &Path) -> Result<(), PatchConfigError> {
+    if context.options.layout == OutputLayout::ContentAddressed {
+        return Ok(());
+    }
+
+    context.sink.create_dir(target_path).map_err(|why| {
+        PatchConfigError::CreateTargetDirectoryFailed(format!(
+            "Unable to create target subdirectory {}: {}",
+            target_path.to_string_lossy(),
+            why
+        ))
+    })
+}
+
+/// Decides where a file's data should be stored. In the tree layout this is
+/// the target path, while in the content-addressed layout it is the object
+/// store, once per distinct digest. Returns None if the data is already stored.
+fn output_destination(context: &Context, target_path: &Path, file_info: &File) -> Option<PathBuf> {
+    match context.options.layout {
+        OutputLayout::Tree => Some(target_path.to_path_buf()),
+        OutputLayout::ContentAddressed => {
+            if context.claim_object(&file_info.digest) {
+                Some(context.object_path(&file_info.digest))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Writes the contents of a file to the output, along with any compressed
+/// variants of it. The variants are recorded in the file's information.
+pub fn write_output_file(
+    context: &Context,
+    target_path: &Path,
+    file_info: &mut File,
+    data: &[u8],
+) -> Result<(), PatchConfigError> {
+    budget::check_file(context, target_path, data.len() as u64)?;
+
+    let destination = output_destination(context, target_path, file_info);
+
+    file_info.compressed =
+        write_compressed_variants(context, destination.as_deref(), file_info, data)?;
+
+    let target_path = match destination {
+        Some(x) => x,
+        None => {
+            context.output_stats.record_deduplicated(data.len() as u64);
+            return Ok(());
+        }
+    };
+
+    // The uncompressed file may be left out when variants replace it
+    if !file_info.compressed.is_empty() && !context.options.compression.keep_uncompressed {
+        return Ok(());
+    }
+
+    write_data(context, &target_path, data)
+}
+
+fn write_data(context: &Context, target_path: &Path, data: &[u8]) -> Result<(), PatchConfigError> {
+    context
+        .scheduler
+        .write(|| commit(context, target_path, |path| context.sink.write(path, data)))
+        .map_err(|why| {
+            PatchConfigError::WriteTargetFileFailed(format!(
+                "Failed to write file {}: {}",
+                target_path.to_string_lossy(),
+                why
+            ))
+        })?;
+
+    context.output_stats.record_written(data.len() as u64);
+
+    Ok(())
+}
+
+/// Places a file in the output. Objects are trusted by name once they are in
+/// the object store, so they are written under a partial name and only
+/// renamed into place once complete. Files of the tree layout are written
+/// straight to their target.
+fn commit<F, T>(context: &Context, target_path: &Path, write: F) -> io::Result<T>
+where
+    F: Fn(&Path) -> io::Result<T>,
+{
+    if context.options.layout != OutputLayout::ContentAddressed {
+        return write(target_path);
+    }
+
+    let partial_path = context::partial_object_path(target_path);
+    let written = write(&partial_path)?;
+    context.sink.rename(&partial_path, target_path)?;
+    Ok(written)
+}
+
+/// Compresses a file with every configured codec. Variants are written next to
+/// the destination with the codec's extension appended, unless they are
+/// already stored.
+fn write_compressed_variants(
+    context: &Context,
+    destination: Option<&Path>,
+    file_info: &File,
+    data: &[u8],
+) -> Result<Vec<CompressedVariant>, PatchConfigError> {
+    let compression = &context.options.compression;
+    let mut variants = Vec::<CompressedVariant>::new();
+
+    if !compression.applies_to(&file_info.name) {
+        return Ok(variants);
+    }
+
+    for codec in &compression.codecs {
+        let compressed = codec.compress(data).map_err(|why| {
+            PatchConfigError::CompressFailed(format!(
+                "Failed to compress {} with {codec:?}: {why}",
+                file_info.name
+            ))
+        })?;
+
+        // A variant which is no smaller is useless while the original is
+        // still published
+        if compression.keep_uncompressed && compressed.len() >= data.len() {
+            continue;
+        }
+
+        let variant_path = match context.options.layout {
+            OutputLayout::Tree => destination.map(|destination| {
+                let mut variant_path = destination.as_os_str().to_owned();
+                variant_path.push(".");
+                variant_path.push(codec.extension());
+                PathBuf::from(variant_path)
+            }),
+            // Variants are claimed separately from the uncompressed object,
+            // since an earlier generation may have stored one without the
+            // other
+            OutputLayout::ContentAddressed => {
+                let object_name = format!("{}.{}", file_info.digest, codec.extension());
+                if context.claim_object(&object_name) {
+                    Some(context.object_path(&object_name))
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(variant_path) = variant_path {
+            write_data(context, &variant_path, &compressed)?;
+        }
+
+        variants.push(CompressedVariant {
+            codec: *codec,
+            size: compressed.len() as u64,
+            digest: digest_of(&compressed),
+        });
+    }
+
+    Ok(variants)
+}
+
+/// Places a source file in the output without copying its data through
+/// memory, using the configured link mode. Only valid for link modes other
+/// than copying.
+pub fn link_output_file(
+    context: &Context,
+    source_path: &Path,
+    target_path: &Path,
+    file_info: &File,
+    size: u64,
+) -> Result<(), PatchConfigError> {
+    budget::check_file(context, target_path, size)?;
+
+    let target_path = match output_destination(context, target_path, file_info) {
+        Some(x) => x,
+        None => {
+            context.output_stats.record_deduplicated(size);
+            return Ok(());
+        }
+    };
+
+    let result = context.scheduler.write(|| {
+        commit(context, &target_path, |path| {
+            context
+                .sink
+                .link(source_path, path, context.options.link_mode)
+        })
+    });
+
+    let linked = result.map_err(|why| {
+        PatchConfigError::LinkTargetFileFailed(format!(
+            "Failed to link {} to {}: {}",
+            source_path.to_string_lossy(),
+            target_path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    context.output_stats.record_written(size);
+    if linked {
+        context.output_stats.record_linked();
+    }
+
+    Ok(())
+}
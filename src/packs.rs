@@ -0,0 +1,84 @@
+use crate::{diff::join, fsobject::*, options::ArchiveExtensions};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// An optional content pack, as listed in meta/packs.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PackInfo {
+    /// Paths, relative to the patch root, of the outermost entries in the
+    /// pack. Everything below them belongs to the pack too, unless it is in
+    /// another pack.
+    pub roots: Vec<String>,
+    /// Number of files a client installs for the pack
+    pub files: u64,
+}
+
+/// Lists every pack used in a patch tree, keyed by pack name
+pub fn pack_index(root: &Directory, extensions: &ArchiveExtensions) -> BTreeMap<String, PackInfo> {
+    let mut packs = BTreeMap::<String, PackInfo>::new();
+    index_directory(root, "", None, extensions, &mut packs);
+    packs
+}
+
+fn index_directory(
+    dir: &Directory,
+    dir_path: &str,
+    parent_pack: Option<&str>,
+    extensions: &ArchiveExtensions,
+    packs: &mut BTreeMap<String, PackInfo>,
+) {
+    for child in &dir.children {
+        match child {
+            FSObject::File(file) => {
+                let path = join(dir_path, &file.name);
+                add_entry(packs, &file.pack, parent_pack, &path, 1);
+            }
+            FSObject::Directory(child_dir) => {
+                let path = join(dir_path, &child_dir.name);
+                add_entry(packs, &child_dir.pack, parent_pack, &path, 0);
+                index_directory(
+                    child_dir,
+                    &path,
+                    child_dir.pack.as_deref(),
+                    extensions,
+                    packs,
+                );
+            }
+            FSObject::Archive(archive) => {
+                let path = join(
+                    dir_path,
+                    &format!("{}.{}", archive.name, extensions.unpacked_output()),
+                );
+                let files = archive.files.len() as u64;
+                add_entry(packs, &archive.pack, parent_pack, &path, files);
+            }
+            FSObject::PackedArchive(archive) => {
+                // The HED is listed as the root, the DAT comes along with it
+                let path = join(dir_path, &archive.header.name);
+                add_entry(packs, &archive.pack, parent_pack, &path, 2);
+            }
+        }
+    }
+}
+
+/// Counts an entry's files towards its pack, and lists it as a root of the
+/// pack when its parent isn't in the same pack
+fn add_entry(
+    packs: &mut BTreeMap<String, PackInfo>,
+    pack: &Option<String>,
+    parent_pack: Option<&str>,
+    path: &str,
+    files: u64,
+) {
+    let pack = match pack {
+        Some(pack) => pack,
+        None => return,
+    };
+
+    let info = packs.entry(pack.clone()).or_default();
+    info.files += files;
+
+    if parent_pack != Some(pack.as_str()) {
+        info.roots.push(path.to_string());
+    }
+}
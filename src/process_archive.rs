@@ -1,64 +1,423 @@
-use crate::{constants::*, error::PatchConfigError, fsobject::*, process_directory::*};
-use rayon::prelude::*;
-use std::path::Path;
+use crate::{
+    context::Context,
+    error::PatchConfigError,
+    fsobject::*,
+    output::*,
+    process_directory::*,
+    process_file::*,
+    report::LayerOrigin,
+    source::{LocalFile, SourceFile},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-pub fn process_new_archive<P>(
-    source_hed_path: P,
+/// Where the entries of an archive in the output come from
+#[derive(Debug)]
+pub enum ArchiveSource {
+    /// A HED, along with the DAT it was paired with while planning
+    Packed {
+        header: SourceFile,
+        data: SourceFile,
+    },
+    /// A directory which holds the archive's files
+    Unpacked(PathBuf),
+}
+
+impl ArchiveSource {
+    /// The HED of a packed archive, or the directory of an unpacked one
+    pub fn path(&self) -> &Path {
+        match self {
+            ArchiveSource::Packed { header, .. } => &header.path,
+            ArchiveSource::Unpacked(path) => path,
+        }
+    }
+}
+
+/// The entries one source of an archive provides, listed before any of them
+/// are written
+enum ListedSource<'a> {
+    Packed {
+        opened: OpenArchive,
+        header_path: &'a Path,
+        data_path: &'a Path,
+    },
+    Unpacked(PlannedDir),
+}
+
+impl ListedSource<'_> {
+    fn entry_names(&self) -> Vec<String> {
+        match self {
+            ListedSource::Packed { opened, .. } => opened.archive.file_names().clone(),
+            ListedSource::Unpacked(plan) => plan.file_names(),
+        }
+    }
+}
+
+/// Writes the entries of every source into one unpacked archive. When sources
+/// share an entry name, the entry from the later source wins. Sources are
+/// given along with the layer they come from.
+pub fn process_archive_sources<P>(
+    sources: &[(usize, ArchiveSource)],
     target_dir_path: P,
     object_name: &str,
-) -> Result<Option<FSObject>, PatchConfigError>
+    context: &Context,
+) -> Result<Archive, PatchConfigError>
 where
     P: AsRef<Path>,
 {
-    // The HED is provided as an argument, so derive the path to its DAT
-    let mut source_dat_path = source_hed_path.as_ref().to_path_buf();
-    source_dat_path.set_extension(ARCHIVE_DATA_EXTENSION);
-
-    let source_dat_path: &Path = source_dat_path.as_ref();
     let target_dir_path: &Path = target_dir_path.as_ref();
-    let source_hed_path: &Path = source_hed_path.as_ref();
 
-    if !source_hed_path.exists() {
-        return Err(PatchConfigError::NoArchiveFile(format!(
-            "Missing archive component {}",
-            source_hed_path.to_string_lossy()
-        )));
+    // The source each entry is taken from is decided before anything is
+    // written, since writing an entry over an earlier source's could fail or,
+    // when it was hardlinked, write through to that source's file
+    let mut listed_sources = Vec::<ListedSource>::new();
+    let mut names = Vec::<String>::new();
+    let mut origins = HashMap::<String, LayerOrigin>::new();
+    let mut winners = HashMap::<String, usize>::new();
+
+    for (index, (layer, source)) in sources.iter().enumerate() {
+        let listed_source = match source {
+            ArchiveSource::Packed { header, data } => ListedSource::Packed {
+                opened: open_archive(&header.path, &data.path, context)?,
+                header_path: &header.path,
+                data_path: &data.path,
+            },
+            ArchiveSource::Unpacked(dir_path) => {
+                ListedSource::Unpacked(plan_unpacked_archive(dir_path, *layer, context)?)
+            }
+        };
+
+        for name in listed_source.entry_names() {
+            match origins.get_mut(&name) {
+                Some(origin) => origin.override_with(*layer),
+                None => {
+                    origins.insert(name.clone(), LayerOrigin::new(*layer));
+                    names.push(name.clone());
+                }
+            }
+            winners.insert(name, index);
+        }
+
+        listed_sources.push(listed_source);
     }
 
-    if !source_dat_path.exists() {
-        return Err(PatchConfigError::NoArchiveFile(format!(
-            "Missing archive component {}",
-            source_dat_path.to_string_lossy()
-        )));
+    let mut written = HashMap::<String, File>::new();
+
+    for (index, listed_source) in listed_sources.into_iter().enumerate() {
+        let wins = |name: &str| winners.get(name) == Some(&index);
+
+        let source_files = match listed_source {
+            ListedSource::Packed {
+                opened,
+                header_path,
+                data_path,
+            } => process_new_archive(
+                &opened,
+                header_path,
+                data_path,
+                target_dir_path,
+                wins,
+                context,
+            )?,
+            ListedSource::Unpacked(mut plan) => {
+                plan.retain_files(wins);
+                process_unpacked_archive(plan, target_dir_path, object_name, context)?
+            }
+        };
+
+        written.extend(
+            source_files
+                .into_iter()
+                .map(|file| (file.name.clone(), file)),
+        );
     }
 
-    // Now that we have the DAT and HED, open the archive
-    let archive =
-        aeco_archive::Archive::open_pair(&source_dat_path, &source_hed_path).map_err(|why| {
-            PatchConfigError::OpenArchiveFailed(format!(
-                "Couldn't open archive {} + {}: {why:?}",
-                source_dat_path.to_string_lossy(),
-                source_hed_path.to_string_lossy()
-            ))
-        })?;
-
-    // Create the directory to which the archive will be unpacked
-    std::fs::create_dir(&target_dir_path).map_err(|why| {
-        PatchConfigError::CreateTargetDirectoryFailed(format!(
-            "Unable to create target directory {}: {}",
-            target_dir_path.to_string_lossy(),
-            why
-        ))
-    })?;
+    // Entries keep the order in which they were first seen
+    let files = names
+        .into_iter()
+        .filter_map(|name| {
+            let file = written.remove(&name)?;
+            if let Some(origin) = origins.remove(&name) {
+                context.record_origin(&target_dir_path.join(&file.name), origin);
+            }
+            Some(file)
+        })
+        .collect();
+
+    Ok(Archive {
+        name: object_name.to_string(),
+        files,
+        pack: None,
+    })
+}
+
+/// Picks the DAT which belongs to a HED from the data files next to it. A
+/// sibling with exactly a configured extension is preferred, and otherwise
+/// extensions are matched case-insensitively, so SOUND.HED may be paired with
+/// SOUND.DAT or SOUND.dat.
+pub(crate) fn pick_archive_data(
+    source_hed_path: &Path,
+    data_paths: &[PathBuf],
+    context: &Context,
+) -> Option<PathBuf> {
+    let extensions = &context.options.archive_extensions;
+    let stem = source_hed_path.file_stem()?;
+
+    for extension in &extensions.data {
+        let candidate = source_hed_path.with_extension(extension);
+        if data_paths.contains(&candidate) {
+            return Some(candidate);
+        }
+    }
+
+    data_paths
+        .iter()
+        .find(|path| {
+            path.file_stem() == Some(stem)
+                && path
+                    .extension()
+                    .is_some_and(|extension| extensions.is_data(extension))
+        })
+        .cloned()
+}
+
+/// An archive which has been opened, along with the files on disk it was
+/// opened from, which must outlive it
+struct OpenArchive {
+    archive: aeco_archive::Archive,
+    _extracted: ExtractedArchive,
+}
+
+/// The HED and DAT of an archive on disk. Archives can only be opened from
+/// disk, so an archive in a packaged build is copied out first.
+struct ExtractedArchive {
+    header: LocalFile,
+    data: LocalFile,
+}
+
+fn extract_archive(
+    source_hed_path: &Path,
+    source_dat_path: &Path,
+    context: &Context,
+) -> Result<ExtractedArchive, PatchConfigError> {
+    context
+        .scheduler
+        .read(|| {
+            Ok(ExtractedArchive {
+                header: context.source.local_file(source_hed_path)?,
+                data: context.source.local_file(source_dat_path)?,
+            })
+        })
+        .map_err(|why: std::io::Error| {
+            open_archive_error(source_hed_path, source_dat_path, why.to_string())
+        })
+}
+
+/// Opens the archive with the given HED and DAT
+fn open_archive(
+    source_hed_path: &Path,
+    source_dat_path: &Path,
+    context: &Context,
+) -> Result<OpenArchive, PatchConfigError> {
+    let extracted = extract_archive(source_hed_path, source_dat_path, context)?;
+    open_extracted_archive(source_hed_path, source_dat_path, extracted, context)
+}
+
+fn open_extracted_archive(
+    source_hed_path: &Path,
+    source_dat_path: &Path,
+    extracted: ExtractedArchive,
+    context: &Context,
+) -> Result<OpenArchive, PatchConfigError> {
+    let archive = context
+        .scheduler
+        .read(|| {
+            aeco_archive::Archive::open_pair(
+                &extracted.data.path().to_path_buf(),
+                &extracted.header.path().to_path_buf(),
+            )
+        })
+        .map_err(|why| open_archive_error(source_hed_path, source_dat_path, format!("{why:?}")))?;
+
+    Ok(OpenArchive {
+        archive,
+        _extracted: extracted,
+    })
+}
+
+fn open_archive_error(
+    source_hed_path: &Path,
+    source_dat_path: &Path,
+    why: String,
+) -> PatchConfigError {
+    PatchConfigError::OpenArchiveFailed(format!(
+        "Couldn't open archive {} + {}: {why}",
+        source_dat_path.to_string_lossy(),
+        source_hed_path.to_string_lossy()
+    ))
+}
+
+/// Copies the HED and DAT of an archive to the output unchanged, and hashes
+/// each of its entries so the manifest can still describe them
+pub fn process_packed_archive(
+    source_hed: &SourceFile,
+    source_dat: &SourceFile,
+    target_dir_path: &Path,
+    object_name: &str,
+    context: &Context,
+) -> Result<PackedArchive, PatchConfigError> {
+    let source_hed_path = source_hed.path.as_path();
+    let source_dat_path = source_dat.path.as_path();
+
+    // An archive in a packaged build is extracted once, both to hash its
+    // entries and to copy its halves
+    let extracted = match context.source.is_on_disk(source_hed_path) {
+        true => None,
+        false => Some(extract_archive(source_hed_path, source_dat_path, context)?),
+    };
+
+    // Both halves keep their original names in the output
+    let header = copy_archive_component(
+        source_hed,
+        extracted.as_ref().map(|extracted| &extracted.header),
+        target_dir_path,
+        context,
+    )?;
+    let data = copy_archive_component(
+        source_dat,
+        extracted.as_ref().map(|extracted| &extracted.data),
+        target_dir_path,
+        context,
+    )?;
+
+    // The entries are only hashed, so an unchanged archive needn't be opened
+    let files = match context.hash_cache.archive(source_hed_path, source_dat_path) {
+        Some(x) => x,
+        None => {
+            let extracted = match extracted {
+                Some(x) => x,
+                None => extract_archive(source_hed_path, source_dat_path, context)?,
+            };
+            let opened =
+                open_extracted_archive(source_hed_path, source_dat_path, extracted, context)?;
+
+            let files = hash_archive_entries(&opened, source_hed_path, source_dat_path, context)?;
+            context
+                .hash_cache
+                .insert_archive(source_hed_path, source_dat_path, &files);
+            files
+        }
+    };
+
+    Ok(PackedArchive {
+        name: object_name.to_string(),
+        header,
+        data,
+        files,
+        pack: None,
+    })
+}
+
+fn hash_archive_entries(
+    opened: &OpenArchive,
+    source_hed_path: &Path,
+    source_dat_path: &Path,
+    context: &Context,
+) -> Result<Vec<File>, PatchConfigError> {
+    let archive = &opened.archive;
 
     let mut files = Vec::<File>::new();
 
-    // Get files from archive in parallel
-    let results: Vec<Result<File, PatchConfigError>> = archive
+    let results: Vec<Result<File, PatchConfigError>> =
+        context
+            .scheduler
+            .map(archive.file_names().iter().collect(), |file_name| {
+                let file_data = context
+                    .scheduler
+                    .read(|| archive.get_file(file_name))
+                    .map_err(|why| {
+                        PatchConfigError::ReadArchiveFailed(format!(
+                            "Couldn't read file {file_name} from archive {} + {}: {why:?}",
+                            source_dat_path.to_string_lossy(),
+                            source_hed_path.to_string_lossy()
+                        ))
+                    })?;
+
+                Ok(File::new(file_name, &file_data))
+            });
+
+    for result in results {
+        files.push(result?);
+    }
+
+    Ok(files)
+}
+
+fn copy_archive_component(
+    source_file: &SourceFile,
+    extracted: Option<&LocalFile>,
+    target_dir_path: &Path,
+    context: &Context,
+) -> Result<File, PatchConfigError> {
+    let component_name = match source_file.path.file_name() {
+        Some(x) => x.to_string_lossy().to_string(),
+        None => {
+            return Err(PatchConfigError::SourceFileNameInvalid(format!(
+                "The archive component at {} has an invalid name",
+                source_file.path.to_string_lossy()
+            )));
+        }
+    };
+
+    let target_path = target_dir_path.join(&component_name);
+    match extracted {
+        Some(extracted) => process_extracted_file(
+            source_file,
+            extracted,
+            &target_path,
+            &component_name,
+            context,
+        ),
+        None => process_new_file(source_file, &target_path, &component_name, context),
+    }
+}
+
+/// Writes the entries of an opened archive which are kept, leaving out those
+/// another source overrides
+fn process_new_archive(
+    opened: &OpenArchive,
+    source_hed_path: &Path,
+    source_dat_path: &Path,
+    target_dir_path: &Path,
+    keep: impl Fn(&str) -> bool,
+    context: &Context,
+) -> Result<Vec<File>, PatchConfigError> {
+    let archive = &opened.archive;
+    let kept_names = archive
         .file_names()
-        .par_iter()
-        .map(|file_name| {
-            let file_data = match archive.get_file(file_name) {
+        .iter()
+        .filter(|name| keep(name))
+        .collect::<Vec<&String>>();
+    let complete = kept_names.len() == archive.file_names().len();
+
+    // Entries are still read to be written, but needn't be hashed again
+    let cached_files = context
+        .hash_cache
+        .archive(source_hed_path, source_dat_path)
+        .map(|files| {
+            files
+                .into_iter()
+                .map(|file| (file.name.clone(), file))
+                .collect::<HashMap<String, File>>()
+        });
+
+    let mut files = Vec::<File>::new();
+
+    let results: Vec<Result<File, PatchConfigError>> =
+        context.scheduler.map(kept_names, |file_name| {
+            let file_data = match context.scheduler.read(|| archive.get_file(file_name)) {
                 Ok(x) => x,
                 Err(why) => {
                     return Err(PatchConfigError::ReadArchiveFailed(format!(
@@ -71,87 +430,72 @@ where
 
             let target_file_path = target_dir_path.join(&file_name);
 
-            let file_info = File::new(file_name, &file_data);
+            let mut file_info = match cached_files
+                .as_ref()
+                .and_then(|cached_files| cached_files.get(file_name))
+            {
+                Some(file) => File::from_digest(file_name, file.digest.clone(), file.size),
+                None => File::new(file_name, &file_data),
+            };
+            file_info.attributes = context.file_attributes(&target_file_path, None);
 
-            std::fs::write(&target_file_path, &file_data).map_err(|why| {
-                PatchConfigError::WriteTargetFileFailed(format!(
-                    "Failed to write file {}: {}",
-                    target_file_path.to_string_lossy(),
-                    why
-                ))
-            })?;
+            write_output_file(context, &target_file_path, &mut file_info, &file_data)?;
 
             Ok(file_info)
-        })
-        .collect();
+        });
 
     for result in results {
         files.push(result?);
     }
 
-    // // Get files from archive sequentially
-    // for file_name in archive.file_names() {
-    //     let file_data = match archive.get_file(&file_name) {
-    //         Ok(x) => x,
-    //         Err(why) => {
-    //             return Err(PatchConfigError::ReadArchiveFailed(format!(
-    //                 "Couldn't read file {file_name} from archive {} + {}: {why:?}",
-    //                 source_dat_path.to_string_lossy(),
-    //                 source_hed_path.as_ref().to_string_lossy()
-    //             )));
-    //         }
-    //     };
-
-    //     let target_file_path = target_dir_path.as_ref().join(&file_name);
-
-    //     let file_info = File::new(file_name, &file_data);
-    //     files.push(file_info);
-
-    //     if let Err(why) = std::fs::write(&target_file_path, &file_data) {
-    //         return Err(PatchConfigError::WriteTargetFileFailed(format!(
-    //             "Failed to write file {}: {}",
-    //             target_file_path.to_string_lossy(),
-    //             why
-    //         )));
-    //     }
-    // }
-
-    let object = FSObject::Archive(Archive {
-        name: object_name.to_string(),
-        files,
-    });
+    // Only an archive whose every entry was hashed can be cached
+    if cached_files.is_none() && complete {
+        context
+            .hash_cache
+            .insert_archive(source_hed_path, source_dat_path, &files);
+    }
 
-    Ok(Some(object))
+    Ok(files)
 }
 
-pub fn process_unpacked_archive<P>(
-    source_dir: P,
-    target_dir: P,
-    object_name: &str,
-) -> Result<Archive, PatchConfigError>
-where
-    P: AsRef<Path>,
-{
-    let mut files = Vec::<File>::new();
-
-    // Unpacked archives are justdirectories that should be represented as
+/// Plans the files of an unpacked archive, which are all it may hold
+fn plan_unpacked_archive(
+    source_dir: &Path,
+    layer: usize,
+    context: &Context,
+) -> Result<PlannedDir, PatchConfigError> {
+    // Unpacked archives are just directories that should be represented as
     // archives
-    let dir = process_dir(&source_dir, &target_dir, object_name)?;
-
-    // An unpacked archive should contain files; collect them
-    for child in dir.children {
-        if let FSObject::File(file) = child {
-            files.push(file);
-        } else {
-            return Err(PatchConfigError::ArchiveContainsDirectory(format!(
-                "The archive directory {} does not contain exclusively files",
-                source_dir.as_ref().to_string_lossy()
-            )));
-        }
+    let layer_dir = LayerDir {
+        layer,
+        path: source_dir.to_path_buf(),
+    };
+    let plan = plan_tree(&[layer_dir], context)?;
+
+    if !plan.has_only_files() {
+        return Err(PatchConfigError::ArchiveContainsDirectory(format!(
+            "The archive directory {} does not contain exclusively files",
+            source_dir.to_string_lossy()
+        )));
     }
 
-    Ok(Archive {
-        name: object_name.to_string(),
-        files,
-    })
+    Ok(plan)
+}
+
+fn process_unpacked_archive(
+    plan: PlannedDir,
+    target_dir: &Path,
+    object_name: &str,
+    context: &Context,
+) -> Result<Vec<File>, PatchConfigError> {
+    let dir = process_planned_dir(plan, target_dir, object_name, None, context)?;
+
+    Ok(dir
+        .children
+        .into_iter()
+        .filter_map(|child| match child {
+            FSObject::File(file) => Some(file),
+            _ => None,
+        })
+        .collect())
 }
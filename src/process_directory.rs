@@ -1,44 +1,218 @@
 use crate::{
-    constants::*, error::PatchConfigError, fsobject::*, process_archive::*, process_file::*,
+    constants::PACK_MARKER_FILE_NAME,
+    context::{check_pack_name, Context},
+    diff,
+    error::PatchConfigError,
+    fsobject::*,
+    options::ArchiveConflictPolicy,
+    output::*,
+    process_archive::*,
+    process_file::*,
+    report::LayerOrigin,
+    source::{SourceEntry, SourceFile, SourceKind},
+    validate::{self, WindowsLimits},
 };
-use rayon::prelude::*;
 use std::{
-    ffi::OsStr,
-    fs::DirEntry,
-    io::Error,
+    collections::HashMap,
     path::{Path, PathBuf},
 };
 
-/// If the argument is a valid archive, get its extensionless name
-fn archive_directory_stem(path: &Path) -> Option<&OsStr> {
-    if path.is_dir() {
-        if let (Some(extension), Some(stem)) = (path.extension(), path.file_stem()) {
-            if extension == UNPACKED_ARCHIVE_EXTENSION {
-                return Some(stem);
+/// A directory of one source layer. Layer 0 is the source itself, and later
+/// layers override earlier ones.
+#[derive(Debug, Clone)]
+pub struct LayerDir {
+    pub layer: usize,
+    pub path: PathBuf,
+}
+
+/// The tasks of a directory and, through its directory tasks, of everything
+/// below it, planned before any output is written
+#[derive(Default)]
+pub struct PlannedDir {
+    tasks: Vec<DirTask>,
+}
+
+impl PlannedDir {
+    /// If everything planned in the directory is a file, as in an unpacked
+    /// archive
+    pub fn has_only_files(&self) -> bool {
+        self.tasks
+            .iter()
+            .all(|task| matches!(task, DirTask::File { .. }))
+    }
+
+    /// Names of the files planned in the directory itself
+    pub fn file_names(&self) -> Vec<String> {
+        self.tasks
+            .iter()
+            .filter_map(|task| match task {
+                DirTask::File { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Drops the planned files whose names aren't kept
+    pub fn retain_files(&mut self, keep: impl Fn(&str) -> bool) {
+        self.tasks.retain(|task| match task {
+            DirTask::File { name, .. } => keep(name),
+            _ => true,
+        });
+    }
+
+    /// Describes every planned name which would break when the patch is
+    /// installed on a Windows client, see [`validate::check_planned_names`].
+    /// The entries of archives are only known once the archives are read, so
+    /// they aren't checked.
+    pub fn windows_name_problems(&self, limits: WindowsLimits, context: &Context) -> Vec<String> {
+        let mut problems = Vec::<String>::new();
+        self.check_names("", limits, context, &mut problems);
+        problems
+    }
+
+    fn check_names(
+        &self,
+        dir_path: &str,
+        limits: WindowsLimits,
+        context: &Context,
+        problems: &mut Vec<String>,
+    ) {
+        let names = self
+            .tasks
+            .iter()
+            .flat_map(|task| task.output_names(context))
+            .collect::<Vec<String>>();
+        validate::check_planned_names(dir_path, &names, limits, problems);
+
+        for task in &self.tasks {
+            if let DirTask::Directory { name, plan, .. } = task {
+                plan.check_names(&diff::join(dir_path, name), limits, context, problems);
             }
         }
     }
-    None
 }
 
-fn process_dir_entry<P>(
-    entry: Result<DirEntry, Error>,
-    source_dir: P,
-    target_dir: P,
-) -> Result<Option<FSObject>, PatchConfigError>
-where
-    P: AsRef<Path>,
-{
-    let entry = entry.map_err(|why| {
-        PatchConfigError::ReadSourceDirectoryEntryFailed(format!(
-            "Couldn't read entry from directory {}: {}",
-            source_dir.as_ref().to_string_lossy(),
-            why
-        ))
-    })?;
+/// One object of the output, along with the source(s) it is generated from
+enum DirTask {
+    Directory {
+        /// The directory in every layer which has it
+        sources: Vec<LayerDir>,
+        name: String,
+        /// What the directory holds, once the whole tree is planned
+        plan: PlannedDir,
+    },
+    File {
+        source: SourceFile,
+        name: String,
+        origin: LayerOrigin,
+    },
+    Archive {
+        /// Sources in the order they are applied, with their layers
+        sources: Vec<(usize, ArchiveSource)>,
+        name: String,
+    },
+    PackedArchive {
+        header: SourceFile,
+        data: SourceFile,
+        name: String,
+        layer: usize,
+    },
+}
+
+impl DirTask {
+    /// The name of the file or directory this task creates in the output
+    fn target_name(&self, context: &Context) -> String {
+        match self {
+            DirTask::Directory { name, .. } | DirTask::File { name, .. } => name.clone(),
+            DirTask::Archive { name, .. } => {
+                format!(
+                    "{name}.{}",
+                    context.options.archive_extensions.unpacked_output()
+                )
+            }
+            DirTask::PackedArchive { header, name, .. } => match header.path.file_name() {
+                Some(file_name) => file_name.to_string_lossy().to_string(),
+                None => name.clone(),
+            },
+        }
+    }
+
+    /// The names of everything this task creates in the output directory,
+    /// which is the HED and DAT for a packed archive
+    fn output_names(&self, context: &Context) -> Vec<String> {
+        match self {
+            DirTask::PackedArchive { data, .. } => match data.path.file_name() {
+                Some(file_name) => vec![
+                    self.target_name(context),
+                    file_name.to_string_lossy().to_string(),
+                ],
+                None => vec![self.target_name(context)],
+            },
+            _ => vec![self.target_name(context)],
+        }
+    }
 
-    let object_path = entry.path();
-    let object_name = match entry.file_name().to_str() {
+    /// Describes where this task's data comes from, for error messages
+    fn describe_sources(&self) -> String {
+        match self {
+            DirTask::File { source, .. } => source.path.to_string_lossy().to_string(),
+            DirTask::PackedArchive { header, .. } => header.path.to_string_lossy().to_string(),
+            DirTask::Directory { sources, .. } => sources
+                .iter()
+                .map(|source| source.path.to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+                .join(" + "),
+            DirTask::Archive { sources, .. } => describe_archive_sources(sources, " + "),
+        }
+    }
+
+    /// Roughly how much data this task reads, for largest-first scheduling,
+    /// from the metadata gathered while planning. Directories come first,
+    /// since their work is spread out further.
+    fn estimated_size(&self) -> u64 {
+        match self {
+            DirTask::Directory { .. } => u64::MAX,
+            DirTask::File { source, .. } => source.metadata.len,
+            DirTask::PackedArchive { header, data, .. } => header.metadata.len + data.metadata.len,
+            DirTask::Archive { sources, .. } => sources
+                .iter()
+                .map(|(_, source)| match source {
+                    ArchiveSource::Packed { header, data } => {
+                        header.metadata.len + data.metadata.len
+                    }
+                    ArchiveSource::Unpacked(_) => 0,
+                })
+                .sum(),
+        }
+    }
+}
+
+fn describe_archive_sources(sources: &[(usize, ArchiveSource)], separator: &str) -> String {
+    sources
+        .iter()
+        .map(|(_, source)| source.path().to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// What a single entry of a source directory contributes to the output
+enum EntryKind {
+    Directory,
+    File,
+    /// A directory holding an unpacked archive, with the archive's name
+    UnpackedArchive(String),
+    /// The HED of a packed archive, with the archive's name
+    PackedArchive(String),
+    /// The DAT of a packed archive, which is paired with its HED
+    ArchiveData,
+}
+
+fn classify_dir_entry(
+    entry: SourceEntry,
+    context: &Context,
+) -> Result<Option<(PathBuf, String, EntryKind)>, PatchConfigError> {
+    let object_path = entry.path;
+    let object_name = match object_path.file_name().and_then(|name| name.to_str()) {
         Some(x) => x.to_string(),
         None => {
             return Err(PatchConfigError::SourceFileNameInvalid(format!(
@@ -48,103 +222,512 @@ where
         }
     };
 
-    let child = if let Some(stem) = archive_directory_stem(&object_path) {
-        // The object is an unpacked archive.
+    let extensions = &context.options.archive_extensions;
+    let extension = object_path.extension();
+
+    // DATs are paired with their HED even when ignored, since they are only
+    // published along with it
+    if entry.kind == SourceKind::File && extension.is_some_and(|x| extensions.is_data(x)) {
+        return Ok(Some((object_path, object_name, EntryKind::ArchiveData)));
+    }
+
+    if context.is_ignored(&object_path) {
+        return Ok(None);
+    }
+
+    // Pack markers describe their directory and aren't published themselves
+    if object_name == PACK_MARKER_FILE_NAME && entry.kind == SourceKind::File {
+        return Ok(None);
+    }
+
+    let stem = object_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string());
+
+    let kind = match entry.kind {
+        SourceKind::Directory => match (extension, stem) {
+            // The object is an unpacked archive
+            (Some(extension), Some(stem)) if extensions.is_unpacked(extension) => {
+                EntryKind::UnpackedArchive(stem)
+            }
+            // The object is a regular directory
+            _ => EntryKind::Directory,
+        },
+        SourceKind::File => match (extension, stem) {
+            // The object is a packed ECO archive
+            (Some(extension), Some(stem)) if extensions.is_metadata(extension) => {
+                EntryKind::PackedArchive(stem)
+            }
+            // The object is a file
+            _ => EntryKind::File,
+        },
+    };
+
+    Ok(Some((object_path, object_name, kind)))
+}
+
+/// Reads a directory in every source layer and decides what each of its
+/// entries becomes in the output. Within a layer, packed and unpacked archives
+/// with the same name are combined or rejected according to the archive
+/// conflict policy. Across layers, later files replace earlier ones, archives
+/// are merged entry by entry, and directories are merged. No two tasks may
+/// write to the same target.
+fn plan_dir(source_dirs: &[LayerDir], context: &Context) -> Result<Vec<DirTask>, PatchConfigError> {
+    let mut tasks = Vec::<DirTask>::new();
+    let mut archives = Vec::<(String, Vec<(usize, ArchiveSource)>)>::new();
 
-        let mut target_path = PathBuf::new();
-        target_path.push(&target_dir);
-        target_path.push(&object_name);
+    // Names are matched case-insensitively across layers, like Windows clients
+    // would match them
+    let mut task_indices = HashMap::<String, usize>::new();
 
-        std::fs::create_dir(&target_path).map_err(|why| {
-            PatchConfigError::CreateTargetDirectoryFailed(format!(
-                "Unable to create target subdirectory {}: {}",
-                target_path.to_string_lossy(),
+    for source_dir in source_dirs {
+        let readdir = context.source.read_dir(&source_dir.path).map_err(|why| {
+            PatchConfigError::ReadSourceDirectoryFailed(format!(
+                "Failed to read directory {}: {}",
+                source_dir.path.to_string_lossy(),
                 why
             ))
         })?;
 
-        Some(FSObject::Archive(process_unpacked_archive(
-            &object_path,
-            &target_path,
-            &stem.to_string_lossy(),
-        )?))
-    } else if object_path.is_dir() {
-        // The object is a regular directory
-
-        let mut target_path = PathBuf::new();
-        target_path.push(&target_dir);
-        target_path.push(&object_name);
-
-        std::fs::create_dir(&target_path).map_err(|why| {
-            PatchConfigError::CreateTargetDirectoryFailed(format!(
-                "Unable to create target subdirectory {}: {}",
-                target_path.to_string_lossy(),
+        let layer = source_dir.layer;
+
+        // HEDs are paired with their DATs once the whole directory is read
+        let mut headers = Vec::<(String, PathBuf)>::new();
+        let mut data_paths = Vec::<PathBuf>::new();
+
+        for entry in readdir {
+            let (source, name, kind) = match classify_dir_entry(entry, context)? {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let key = name.to_lowercase();
+
+            // Only tasks from earlier layers are overridden; names within one
+            // layer which only differ by case are left for validation
+            let existing = task_indices
+                .get(&key)
+                .copied()
+                .filter(|&index| task_layer(&tasks[index]) < layer);
+
+            match (kind, existing.map(|index| &mut tasks[index])) {
+                (EntryKind::Directory, Some(DirTask::Directory { sources, .. })) => {
+                    sources.push(LayerDir {
+                        layer,
+                        path: source,
+                    });
+                }
+                (
+                    EntryKind::File,
+                    Some(DirTask::File {
+                        source: existing_source,
+                        name: existing_name,
+                        origin,
+                    }),
+                ) => {
+                    *existing_source = source_file(&source, context)?;
+                    *existing_name = name;
+                    origin.override_with(layer);
+                }
+                (EntryKind::Directory, Some(task)) | (EntryKind::File, Some(task)) => {
+                    return Err(PatchConfigError::LayerConflict(format!(
+                        "{} is a directory in one layer and a file in another: {}",
+                        name,
+                        [
+                            task.describe_sources(),
+                            source.to_string_lossy().to_string()
+                        ]
+                        .join(" and ")
+                    )));
+                }
+                (EntryKind::Directory, None) => {
+                    task_indices.insert(key, tasks.len());
+                    tasks.push(DirTask::Directory {
+                        sources: vec![LayerDir {
+                            layer,
+                            path: source,
+                        }],
+                        name,
+                        plan: PlannedDir::default(),
+                    });
+                }
+                (EntryKind::File, None) => {
+                    task_indices.insert(key, tasks.len());
+                    tasks.push(DirTask::File {
+                        source: source_file(&source, context)?,
+                        name,
+                        origin: LayerOrigin::new(layer),
+                    });
+                }
+                (EntryKind::UnpackedArchive(stem), _) => {
+                    add_archive_source(&mut archives, stem, layer, ArchiveSource::Unpacked(source));
+                }
+                (EntryKind::PackedArchive(stem), _) => headers.push((stem, source)),
+                (EntryKind::ArchiveData, _) => data_paths.push(source),
+            }
+        }
+
+        for (stem, header_path) in headers {
+            let data_path =
+                pick_archive_data(&header_path, &data_paths, context).ok_or_else(|| {
+                    PatchConfigError::NoArchiveFile(format!(
+                        "Missing data file for archive {}",
+                        header_path.to_string_lossy()
+                    ))
+                })?;
+
+            let archive_source = ArchiveSource::Packed {
+                header: source_file(&header_path, context)?,
+                data: source_file(&data_path, context)?,
+            };
+            add_archive_source(&mut archives, stem, layer, archive_source);
+        }
+    }
+
+    for (name, sources) in archives {
+        let sources = order_archive_sources(&name, sources, context)?;
+
+        let keep_packed = sources.iter().any(|(_, source)| match source {
+            ArchiveSource::Packed { header, .. } => context.keep_packed(&header.path),
+            ArchiveSource::Unpacked(_) => false,
+        });
+
+        if !keep_packed {
+            tasks.push(DirTask::Archive { sources, name });
+        } else if let [(layer, ArchiveSource::Packed { header, data })] = sources.as_slice() {
+            tasks.push(DirTask::PackedArchive {
+                header: header.clone(),
+                data: data.clone(),
+                name,
+                layer: *layer,
+            });
+        } else {
+            // Merging requires unpacking, so an archive can't be both
+            return Err(PatchConfigError::ArchiveConflict(format!(
+                "Archive {name} should be kept packed, but it is merged from {}",
+                describe_archive_sources(&sources, " and ")
+            )));
+        }
+    }
+
+    // An archive is unpacked into <name>.archive, which could also be the name
+    // of something else in the same directory
+    let mut targets = HashMap::<String, usize>::new();
+    for (index, task) in tasks.iter().enumerate() {
+        if let Some(other) = targets.insert(task.target_name(context), index) {
+            return Err(PatchConfigError::ArchiveConflict(format!(
+                "Both {} and {} would be written to {}",
+                tasks[other].describe_sources(),
+                task.describe_sources(),
+                task.target_name(context)
+            )));
+        }
+    }
+
+    Ok(tasks)
+}
+
+/// Adds a source to the archive with the same name, matched
+/// case-insensitively, or to a new archive
+fn add_archive_source(
+    archives: &mut Vec<(String, Vec<(usize, ArchiveSource)>)>,
+    stem: String,
+    layer: usize,
+    source: ArchiveSource,
+) {
+    let stem_key = stem.to_lowercase();
+    match archives
+        .iter_mut()
+        .find(|(name, _)| name.to_lowercase() == stem_key)
+    {
+        Some((_, sources)) => sources.push((layer, source)),
+        None => archives.push((stem, vec![(layer, source)])),
+    }
+}
+
+/// The latest layer a directory or file task has been taken from
+fn task_layer(task: &DirTask) -> usize {
+    match task {
+        DirTask::Directory { sources, .. } => sources.last().map_or(0, |source| source.layer),
+        DirTask::File { origin, .. } => origin.layer,
+        DirTask::Archive { sources, .. } => sources.last().map_or(0, |(layer, _)| *layer),
+        DirTask::PackedArchive { layer, .. } => *layer,
+    }
+}
+
+/// Puts the sources of an archive in the order they are applied, checking
+/// the sources each layer provides against the archive conflict policy
+fn order_archive_sources(
+    name: &str,
+    mut sources: Vec<(usize, ArchiveSource)>,
+    context: &Context,
+) -> Result<Vec<(usize, ArchiveSource)>, PatchConfigError> {
+    let mut layers = sources
+        .iter()
+        .map(|(layer, _)| *layer)
+        .collect::<Vec<usize>>();
+    layers.dedup();
+
+    for layer in layers {
+        let layer_sources = sources
+            .iter()
+            .filter(|(source_layer, _)| *source_layer == layer)
+            .collect::<Vec<_>>();
+
+        if layer_sources.len() < 2 {
+            continue;
+        }
+
+        let packed_count = layer_sources
+            .iter()
+            .filter(|(_, source)| matches!(source, ArchiveSource::Packed { .. }))
+            .count();
+
+        let mergeable = layer_sources.len() == 2
+            && packed_count == 1
+            && context.options.archive_conflicts == ArchiveConflictPolicy::MergeUnpacked;
+
+        if !mergeable {
+            return Err(PatchConfigError::ArchiveConflict(format!(
+                "Archive {name} is provided by more than one source: {}",
+                layer_sources
+                    .iter()
+                    .map(|(_, source)| source.path().to_string_lossy().to_string())
+                    .collect::<Vec<String>>()
+                    .join(" and ")
+            )));
+        }
+    }
+
+    // Later layers are applied last, and within a layer the unpacked folder
+    // is applied after the packed archive so its files override its entries
+    sources.sort_by_key(|(layer, source)| (*layer, matches!(source, ArchiveSource::Unpacked(_))));
+
+    Ok(sources)
+}
+
+/// Reads the pack named by the marker file in a source directory, if it has
+/// one
+fn read_pack_marker(
+    source_dir: &Path,
+    context: &Context,
+) -> Result<Option<String>, PatchConfigError> {
+    let marker_path = source_dir.join(PACK_MARKER_FILE_NAME);
+
+    let is_file = context
+        .source
+        .metadata(&marker_path)
+        .is_ok_and(|metadata| metadata.is_file());
+    if !is_file {
+        return Ok(None);
+    }
+
+    let pack = context
+        .source
+        .read(&marker_path)
+        .and_then(|data| {
+            String::from_utf8(data)
+                .map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))
+        })
+        .map_err(|why| {
+            PatchConfigError::ReadSourceFileFailed(format!(
+                "Failed to read file {}: {}",
+                marker_path.to_string_lossy(),
                 why
             ))
         })?;
 
-        Some(FSObject::Directory(process_dir(
-            &object_path,
-            &target_path,
-            &object_name,
-        )?))
-    } else if object_path.is_file() {
-        // The object is a file
-
-        let mut target_path = PathBuf::new();
-        target_path.push(&target_dir);
-        target_path.push(&object_name);
-
-        process_new_file(&object_path, &target_path, &object_name)?
-    } else {
-        None
+    let pack = pack.trim();
+    check_pack_name(pack).map_err(|why| {
+        PatchConfigError::InvalidPack(format!(
+            "{}: {}",
+            marker_path.to_string_lossy(),
+            why.to_string()
+        ))
+    })?;
+
+    Ok(Some(pack.to_string()))
+}
+
+/// Fails if the root of the source or a layer has a pack marker, which would
+/// put the whole patch in a pack rather than leave it for every client
+pub fn check_root_pack_markers(
+    source_dirs: &[LayerDir],
+    context: &Context,
+) -> Result<(), PatchConfigError> {
+    for source_dir in source_dirs {
+        if let Some(pack) = read_pack_marker(&source_dir.path, context)? {
+            return Err(PatchConfigError::InvalidPack(format!(
+                "{}: the root can't be put in pack {pack}, mark its directories instead",
+                source_dir
+                    .path
+                    .join(PACK_MARKER_FILE_NAME)
+                    .to_string_lossy()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Decides which pack a task's output belongs to. A directory's marker file
+/// comes first, then the pack globs, and otherwise the pack of the parent
+/// directory is inherited. Later layers take precedence over earlier ones.
+fn task_pack(
+    task: &DirTask,
+    inherited: Option<&str>,
+    context: &Context,
+) -> Result<Option<String>, PatchConfigError> {
+    let sources: Vec<&Path> = match task {
+        DirTask::Directory { sources, .. } => {
+            for source in sources.iter().rev() {
+                if let Some(pack) = read_pack_marker(&source.path, context)? {
+                    return Ok(Some(pack));
+                }
+            }
+
+            sources.iter().map(|source| source.path.as_path()).collect()
+        }
+        DirTask::File { source, .. } => vec![source.path.as_path()],
+        DirTask::PackedArchive { header, .. } => vec![header.path.as_path()],
+        DirTask::Archive { sources, .. } => {
+            sources.iter().map(|(_, source)| source.path()).collect()
+        }
+    };
+
+    let pack = sources
+        .iter()
+        .rev()
+        .find_map(|source| context.pack_of(source))
+        .or(inherited);
+
+    Ok(pack.map(|pack| pack.to_string()))
+}
+
+fn tag_files(files: &mut [File], pack: &Option<String>) {
+    for file in files {
+        file.pack = pack.clone();
+    }
+}
+
+fn process_dir_task(
+    task: DirTask,
+    target_dir: &Path,
+    pack: Option<&str>,
+    context: &Context,
+) -> Result<FSObject, PatchConfigError> {
+    let mut target_path = PathBuf::new();
+    target_path.push(&target_dir);
+    target_path.push(task.target_name(context));
+
+    let pack = task_pack(&task, pack, context)?;
+
+    let child = match task {
+        DirTask::Directory { name, plan, .. } => {
+            create_output_dir(context, &target_path)?;
+            FSObject::Directory(process_planned_dir(
+                plan,
+                &target_path,
+                &name,
+                pack.as_deref(),
+                context,
+            )?)
+        }
+        DirTask::File {
+            source,
+            name,
+            origin,
+        } => {
+            let mut file = process_new_file(&source, &target_path, &name, context)?;
+            file.pack = pack;
+            context.record_origin(&target_path, origin);
+            FSObject::File(file)
+        }
+        DirTask::Archive { sources, name } => {
+            create_output_dir(context, &target_path)?;
+            let mut archive = process_archive_sources(&sources, &target_path, &name, context)?;
+            tag_files(&mut archive.files, &pack);
+            archive.pack = pack;
+            FSObject::Archive(archive)
+        }
+        DirTask::PackedArchive {
+            header,
+            data,
+            name,
+            layer,
+        } => {
+            // The HED and DAT are written next to each other
+            let mut archive = process_packed_archive(&header, &data, target_dir, &name, context)?;
+            for component in [&archive.header, &archive.data] {
+                context.record_origin(&target_dir.join(&component.name), LayerOrigin::new(layer));
+            }
+            tag_files(&mut archive.files, &pack);
+            archive.header.pack = pack.clone();
+            archive.data.pack = pack.clone();
+            archive.pack = pack;
+            FSObject::PackedArchive(archive)
+        }
     };
 
     Ok(child)
 }
 
-pub fn process_dir<P>(
-    source_dir: P,
+/// Plans the output for a directory and everything below it, which is merged
+/// from the same directory in every source layer that has it. Nothing is
+/// written until the plan is processed, see [`process_planned_dir`].
+pub fn plan_tree(
+    source_dirs: &[LayerDir],
+    context: &Context,
+) -> Result<PlannedDir, PatchConfigError> {
+    let tasks = plan_dir(source_dirs, context)?;
+
+    let tasks = context
+        .scheduler
+        .map(tasks, |mut task| {
+            if let DirTask::Directory { sources, plan, .. } = &mut task {
+                *plan = plan_tree(sources, context)?;
+            }
+            Ok(task)
+        })
+        .into_iter()
+        .collect::<Result<Vec<DirTask>, PatchConfigError>>()?;
+
+    Ok(PlannedDir { tasks })
+}
+
+/// Generates the output for a planned directory, see [`plan_tree`].
+/// Everything in the directory belongs to the given pack unless it is put in
+/// another one.
+pub fn process_planned_dir<P>(
+    plan: PlannedDir,
     target_dir: P,
     object_name: &str,
+    pack: Option<&str>,
+    context: &Context,
 ) -> Result<Directory, PatchConfigError>
 where
     P: AsRef<Path>,
 {
-    let source_dir = source_dir.as_ref();
     let target_dir = target_dir.as_ref();
 
     let mut children = Vec::<FSObject>::new();
 
-    let readdir = std::fs::read_dir(&source_dir).map_err(|why| {
-        PatchConfigError::ReadSourceDirectoryFailed(format!(
-            "Failed to read directory {}: {}",
-            source_dir.to_string_lossy(),
-            why
-        ))
-    })?;
+    let tasks = plan.tasks;
 
-    // Process dirs in parallel
-    let results: Vec<Result<Option<FSObject>, PatchConfigError>> = readdir
-        .par_bridge()
-        .map(|entry| process_dir_entry(entry, &source_dir, &target_dir))
-        .collect();
+    let results: Vec<Result<FSObject, PatchConfigError>> =
+        context
+            .scheduler
+            .map_largest_first(tasks, DirTask::estimated_size, |task| {
+                process_dir_task(task, target_dir, pack, context)
+            });
 
     for result in results {
-        if let Some(child) = result? {
-            children.push(child)
-        }
+        children.push(result?);
     }
 
-    // // Process dirs sequentially
-    // for entry in readdir {
-    //     if let Some(child) = process_dir_entry(entry, &source_dir, &target_dir)? {
-    //         children.push(child);
-    //     }
-    // }
-
     Ok(Directory {
         name: object_name.to_string(),
         children,
+        pack: pack.map(|pack| pack.to_string()),
     })
 }
@@ -1,62 +1,173 @@
-use crate::{constants::*, error::PatchConfigError, fsobject::*, process_archive::*};
+use crate::{
+    context::Context,
+    error::PatchConfigError,
+    fsobject::*,
+    options::LinkMode,
+    output::*,
+    source::{LocalFile, SourceFile, SourceMetadata},
+};
 
-use std::path::{Path, PathBuf};
+use std::{io::BufReader, path::Path};
 
-pub fn process_new_file<P>(
-    source_file_path: P,
-    target_file_path: P,
+/// Looks up the metadata of a source file, once, while planning
+pub fn source_file(path: &Path, context: &Context) -> Result<SourceFile, PatchConfigError> {
+    let metadata = context.source.metadata(path).map_err(|why| {
+        PatchConfigError::ReadSourceFileFailed(format!(
+            "Failed to read file {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    Ok(SourceFile {
+        path: path.to_path_buf(),
+        metadata,
+    })
+}
+
+pub fn process_new_file(
+    source_file: &SourceFile,
+    target_file_path: &Path,
     object_name: &str,
-) -> Result<Option<FSObject>, PatchConfigError>
-where
-    P: AsRef<Path>,
-{
-    // Handle ECO archives
-    if let Some(extension) = source_file_path.as_ref().extension() {
-        // Ignore DAT files, they will be processed when their HED is reached
-        if extension == ARCHIVE_DATA_EXTENSION {
-            return Ok(None);
-        } else if extension == ARCHIVE_METADATA_EXTENSION {
-            // Make a dir with the extension .archive instead
-            if let (Some(target_parent), Some(target_stem)) = (
-                target_file_path.as_ref().parent(),    /* Get containing path */
-                target_file_path.as_ref().file_stem(), /* Get file name without extension */
-            ) {
-                // Make a target dir as <path>/<archive name>.<new archive extension>
-                let mut target_dir = PathBuf::new();
-                target_dir.push(target_parent);
-                target_dir.push(target_stem);
-                target_dir.set_extension(UNPACKED_ARCHIVE_EXTENSION);
-
-                if let Some(object_name) = object_name.split('.').next() {
-                    return process_new_archive(
-                        source_file_path.as_ref(),
-                        &target_dir,
-                        object_name,
-                    );
-                }
-            }
-        }
+    context: &Context,
+) -> Result<File, PatchConfigError> {
+    let disk_path = context
+        .source
+        .is_on_disk(&source_file.path)
+        .then_some(source_file.path.as_path());
+
+    process_file_at(
+        source_file,
+        disk_path,
+        target_file_path,
+        object_name,
+        context,
+    )
+}
+
+/// Like [`process_new_file`], for a source file which was already extracted
+/// to disk, such as either half of an archive in a packaged build, so that it
+/// isn't extracted a second time
+pub fn process_extracted_file(
+    source_file: &SourceFile,
+    extracted: &LocalFile,
+    target_file_path: &Path,
+    object_name: &str,
+    context: &Context,
+) -> Result<File, PatchConfigError> {
+    process_file_at(
+        source_file,
+        Some(extracted.path()),
+        target_file_path,
+        object_name,
+        context,
+    )
+}
+
+/// Processes a source file, reading it from a copy on disk if it has one
+fn process_file_at(
+    source_file: &SourceFile,
+    disk_path: Option<&Path>,
+    target_file_path: &Path,
+    object_name: &str,
+    context: &Context,
+) -> Result<File, PatchConfigError> {
+    let source_file_path = source_file.path.as_path();
+    let metadata = &source_file.metadata;
+
+    // Files which get compressed variants are read in full either way, so
+    // there's no point in linking them. Files which aren't on disk, such as
+    // those in packaged builds, can't be linked.
+    if let Some(disk_path) = disk_path.filter(|_| {
+        context.options.link_mode != LinkMode::Copy
+            && !context.options.compression.applies_to(object_name)
+    }) {
+        let mut file_info =
+            process_linked_file(disk_path, target_file_path, object_name, metadata, context)?;
+        file_info.attributes = context.file_attributes(target_file_path, Some(metadata));
+        return Ok(file_info);
     }
 
     // To avoid having to read the file twice, read it all into memory, hash
     // it, and then write the data to the target file.
-    let data = std::fs::read(&source_file_path).map_err(|why| {
+    let data = context
+        .scheduler
+        .read(|| match disk_path {
+            Some(disk_path) => std::fs::read(disk_path),
+            None => context.source.read(source_file_path),
+        })
+        .map_err(|why| {
+            PatchConfigError::ReadSourceFileFailed(format!(
+                "Failed to read file {}: {}",
+                source_file_path.to_string_lossy(),
+                why
+            ))
+        })?;
+
+    let mut file_info = match context
+        .hash_cache
+        .file(object_name, source_file_path, metadata)
+    {
+        Some(x) => x,
+        None => {
+            let file_info = File::new(object_name, &data);
+            context
+                .hash_cache
+                .insert_file(source_file_path, metadata, &file_info);
+            file_info
+        }
+    };
+    file_info.attributes = context.file_attributes(target_file_path, Some(metadata));
+
+    write_output_file(context, target_file_path, &mut file_info, &data)?;
+
+    Ok(file_info)
+}
+
+/// Hashes a file while streaming it from disk, unless the hash cache knows its
+/// digest, then links it into the output instead of writing a copy
+fn process_linked_file(
+    source_file_path: &Path,
+    target_file_path: &Path,
+    object_name: &str,
+    metadata: &SourceMetadata,
+    context: &Context,
+) -> Result<File, PatchConfigError> {
+    let read_error = |why| {
         PatchConfigError::ReadSourceFileFailed(format!(
             "Failed to read file {}: {}",
-            source_file_path.as_ref().to_string_lossy(),
+            source_file_path.to_string_lossy(),
             why
         ))
-    })?;
+    };
 
-    let file_info = File::new(object_name, &data);
+    let file_info = match context
+        .hash_cache
+        .file(object_name, source_file_path, metadata)
+    {
+        Some(x) => x,
+        None => {
+            let file_info = context
+                .scheduler
+                .read(|| {
+                    let source_file = std::fs::File::open(source_file_path)?;
+                    File::from_reader(object_name, BufReader::new(source_file))
+                })
+                .map_err(read_error)?;
+            context
+                .hash_cache
+                .insert_file(source_file_path, metadata, &file_info);
+            file_info
+        }
+    };
 
-    std::fs::write(&target_file_path, &data).map_err(|why| {
-        PatchConfigError::WriteTargetFileFailed(format!(
-            "Failed to write file {}: {}",
-            target_file_path.as_ref().to_string_lossy(),
-            why
-        ))
-    })?;
+    link_output_file(
+        context,
+        source_file_path,
+        target_file_path,
+        &file_info,
+        file_info.size,
+    )?;
 
-    Ok(Some(FSObject::File(file_info)))
+    Ok(file_info)
 }
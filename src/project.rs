@@ -0,0 +1,122 @@
+use crate::{error::PatchConfigError, options::GenerateOptions};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A project file, which records everything needed to repeat a generation.
+/// Relative paths in the file are relative to the directory containing it.
+///
+/// ```toml
+/// source = "eco"
+/// output = "releases/aeco-patch"
+/// channel = "test"
+/// maintenance = false
+/// ignore = ["*.log", "screenshots"]
+/// packed_archives = ["sound.hed"]
+/// layout = "ContentAddressed"
+///
+/// [compression]
+/// codecs = ["Zstd"]
+///
+/// [packs]
+/// hires = ["textures/hires", "hd_*.hed"]
+///
+/// [attributes]
+/// background = ["music/**"]
+///
+/// [launcher]
+/// path = "build/launcher.exe"
+/// version = "2.1.0"
+///
+/// [budget]
+/// max_delta_bytes = 500_000_000
+///
+/// [parallelism]
+/// max_concurrent_reads = 4
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProjectConfig {
+    /// Path to the ECO folder. May also be a packaged build, see
+    /// [`crate::source::package_path`].
+    pub source: PathBuf,
+    /// Path in which to generate configuration files
+    pub output: PathBuf,
+    /// Release channel to generate, such as live or test. When set, the
+    /// output holds every channel, see [`crate::channel`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(flatten)]
+    pub options: GenerateOptions,
+}
+
+impl ProjectConfig {
+    pub fn load<P>(path: P) -> Result<Self, PatchConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let text = std::fs::read_to_string(path).map_err(|why| {
+            PatchConfigError::ProjectFailed(format!(
+                "Unable to read project file {}: {}",
+                path.to_string_lossy(),
+                why
+            ))
+        })?;
+
+        let mut project: ProjectConfig = toml::from_str(&text).map_err(|why| {
+            PatchConfigError::ProjectFailed(format!(
+                "Project file {} is invalid: {}",
+                path.to_string_lossy(),
+                why
+            ))
+        })?;
+
+        // Make paths independent of the directory the tools are run from
+        if let Some(project_dir) = path.parent() {
+            project.source = project_dir.join(&project.source);
+            project.output = project_dir.join(&project.output);
+            project.options.layers = project
+                .options
+                .layers
+                .iter()
+                .map(|layer| project_dir.join(layer))
+                .collect();
+            if let Some(launcher) = &mut project.options.launcher {
+                launcher.path = project_dir.join(&launcher.path);
+            }
+            project.options.signing_key = project
+                .options
+                .signing_key
+                .map(|signing_key| project_dir.join(signing_key));
+            project.options.previous_output = project
+                .options
+                .previous_output
+                .map(|previous_output| project_dir.join(previous_output));
+            project.options.hash_cache = project
+                .options
+                .hash_cache
+                .map(|hash_cache| project_dir.join(hash_cache));
+        }
+
+        Ok(project)
+    }
+
+    pub fn save<P>(&self, path: P) -> Result<(), PatchConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let text = toml::to_string(self).map_err(|why| {
+            PatchConfigError::ProjectFailed(format!("Failed to serialize project: {why}"))
+        })?;
+
+        std::fs::write(path, text).map_err(|why| {
+            PatchConfigError::ProjectFailed(format!(
+                "Unable to write project file {}: {}",
+                path.to_string_lossy(),
+                why
+            ))
+        })
+    }
+}
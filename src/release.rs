@@ -0,0 +1,324 @@
+use crate::{
+    diff::ChangeSummary, error::PatchConfigError, fsobject::Directory, options::PublishedLayout,
+};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Describes the release a generation produces. Written to the metadata as
+/// release.json, and appended to the history in releases.json as a
+/// [`HistoryEntry`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Release {
+    /// A semantic version such as 1.4.3, or a build number such as 42
+    pub id: String,
+    /// Digest of the release's patchlist.json, which identifies its contents
+    pub root_digest: String,
+    /// When the release was generated, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Files which changed since the previous release
+    pub changes: ChangeSummary,
+}
+
+/// A release as listed in the history in releases.json. The history grows with
+/// every release, so it only counts the changed files, which are listed in
+/// that release's release.json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub root_digest: String,
+    pub timestamp: u64,
+    pub changes: ChangeCounts,
+}
+
+/// How many files a release added, modified and removed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChangeCounts {
+    #[serde(deserialize_with = "deserialize_count")]
+    pub added: usize,
+    #[serde(deserialize_with = "deserialize_count")]
+    pub modified: usize,
+    #[serde(deserialize_with = "deserialize_count")]
+    pub removed: usize,
+}
+
+impl From<&Release> for HistoryEntry {
+    fn from(release: &Release) -> Self {
+        HistoryEntry {
+            id: release.id.clone(),
+            root_digest: release.root_digest.clone(),
+            timestamp: release.timestamp,
+            changes: ChangeCounts {
+                added: release.changes.added.len(),
+                modified: release.changes.modified.len(),
+                removed: release.changes.removed.len(),
+            },
+        }
+    }
+}
+
+/// Histories written before only counts were kept list every changed path
+fn deserialize_count<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Count {
+        Count(usize),
+        Paths(Vec<String>),
+    }
+
+    Ok(match Count::deserialize(deserializer)? {
+        Count::Count(count) => count,
+        Count::Paths(paths) => paths.len(),
+    })
+}
+
+/// A release identifier, parsed so releases can be ordered
+#[derive(Debug, PartialEq, Eq)]
+enum ReleaseId {
+    Build(u64),
+    /// Major, minor and patch, along with an optional pre-release tag.
+    /// Pre-release tags are compared as semantic versioning describes, see
+    /// [`compare_pre_release`].
+    Version(u64, u64, u64, Option<String>),
+}
+
+impl ReleaseId {
+    fn parse(id: &str) -> Option<Self> {
+        if let Ok(build) = id.parse::<u64>() {
+            return Some(ReleaseId::Build(build));
+        }
+
+        // Build metadata after a + doesn't affect ordering
+        let id = id.split('+').next()?;
+        let (core, pre_release) = match id.split_once('-') {
+            Some((core, pre_release)) if !pre_release.is_empty() => {
+                (core, Some(pre_release.to_string()))
+            }
+            Some(_) => return None,
+            None => (id, None),
+        };
+
+        let parts = core
+            .split('.')
+            .map(|part| part.parse::<u64>().ok())
+            .collect::<Option<Vec<u64>>>()?;
+
+        match parts.as_slice() {
+            [major, minor, patch] => Some(ReleaseId::Version(*major, *minor, *patch, pre_release)),
+            _ => None,
+        }
+    }
+
+    /// Orders two ids of the same kind. Build numbers and versions can't be
+    /// compared with each other.
+    fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (ReleaseId::Build(a), ReleaseId::Build(b)) => Some(a.cmp(b)),
+            (
+                ReleaseId::Version(a_major, a_minor, a_patch, a_pre),
+                ReleaseId::Version(b_major, b_minor, b_patch, b_pre),
+            ) => {
+                let core = (a_major, a_minor, a_patch).cmp(&(b_major, b_minor, b_patch));
+                // A pre-release comes before the release it leads up to
+                let pre_release = match (a_pre, b_pre) {
+                    (None, None) => Ordering::Equal,
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (Some(a), Some(b)) => compare_pre_release(a, b),
+                };
+                Some(core.then(pre_release))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Compares pre-release tags one dot-separated identifier at a time. Numeric
+/// identifiers compare as numbers and come before alphanumeric ones, which
+/// compare as text, and a tag which starts another comes first, so
+/// alpha < alpha.1 < alpha.beta < beta < beta.2 < beta.11 < rc.1.
+fn compare_pre_release(a: &str, b: &str) -> Ordering {
+    fn is_numeric(identifier: &str) -> bool {
+        !identifier.is_empty() && identifier.bytes().all(|byte| byte.is_ascii_digit())
+    }
+
+    let mut a_identifiers = a.split('.');
+    let mut b_identifiers = b.split('.');
+
+    loop {
+        let ordering = match (a_identifiers.next(), b_identifiers.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            // Without leading zeros, a longer number is a larger one, which
+            // also orders numbers too large to parse
+            (Some(a), Some(b)) => match (is_numeric(a), is_numeric(b)) {
+                (true, true) => (a.len(), a).cmp(&(b.len(), b)),
+                (true, false) => Ordering::Less,
+                (false, true) => Ordering::Greater,
+                (false, false) => a.cmp(b),
+            },
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Decides the id of the next release. When no id is requested, the build
+/// number after the latest release is used, which requires earlier releases to
+/// also use build numbers.
+pub fn next_release_id(
+    requested: Option<&str>,
+    history: &[HistoryEntry],
+) -> Result<String, PatchConfigError> {
+    let latest = history.last();
+
+    let latest_id = match latest {
+        Some(latest) => Some(ReleaseId::parse(&latest.id).ok_or_else(|| {
+            PatchConfigError::ReleaseFailed(format!(
+                "The latest release has an invalid id: {}",
+                latest.id
+            ))
+        })?),
+        None => None,
+    };
+
+    let requested = match requested {
+        Some(requested) => requested,
+        None => {
+            return match latest_id {
+                None => Ok("1".to_string()),
+                Some(ReleaseId::Build(build)) => Ok((build + 1).to_string()),
+                Some(_) => Err(PatchConfigError::ReleaseFailed(format!(
+                    "The latest release uses the version {}, so the next version must be given",
+                    latest.map(|latest| latest.id.as_str()).unwrap_or_default()
+                ))),
+            };
+        }
+    };
+
+    let requested_id = ReleaseId::parse(requested).ok_or_else(|| {
+        PatchConfigError::ReleaseFailed(format!(
+            "Release id {requested} is neither a semantic version nor a build number"
+        ))
+    })?;
+
+    if history.iter().any(|release| release.id == requested) {
+        return Err(PatchConfigError::ReleaseFailed(format!(
+            "Release {requested} has already been published"
+        )));
+    }
+
+    if let (Some(latest), Some(latest_id)) = (latest, latest_id) {
+        match requested_id.compare(&latest_id) {
+            Some(Ordering::Greater) => {}
+            Some(_) => {
+                return Err(PatchConfigError::ReleaseFailed(format!(
+                    "Release {requested} must come after the latest release {}",
+                    latest.id
+                )));
+            }
+            None => {
+                return Err(PatchConfigError::ReleaseFailed(format!(
+                    "Release {requested} can't follow release {}, since build numbers and versions can't be mixed",
+                    latest.id
+                )));
+            }
+        }
+    }
+
+    Ok(requested.to_string())
+}
+
+/// Reads the release history of an earlier output. Outputs generated before
+/// releases were recorded have an empty history.
+pub fn load_history(previous_meta_dir: &Path) -> Result<Vec<HistoryEntry>, PatchConfigError> {
+    let path = previous_meta_dir.join(crate::RELEASE_HISTORY_FILE_NAME);
+
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    read_json(&path)
+}
+
+/// Finds an earlier output next to the target directory, whose history a new
+/// release generated without a previous output would likely be meant to
+/// continue. Of several, the last by name is returned.
+pub fn find_sibling_output(target_dir: &Path) -> Option<PathBuf> {
+    let parent = target_dir.parent()?;
+    let parent = match parent.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => parent,
+    };
+
+    std::fs::read_dir(parent)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.join(crate::METADATA_DIR_NAME)
+                .join(crate::RELEASE_HISTORY_FILE_NAME)
+                .is_file()
+        })
+        .max()
+}
+
+/// Reads the release described by a metadata directory
+pub fn load_release(meta_dir: &Path) -> Result<Release, PatchConfigError> {
+    read_json(&meta_dir.join(crate::RELEASE_FILE_NAME))
+}
+
+/// Reads the patch tree of an earlier output
+pub fn load_patchlist(previous_meta_dir: &Path) -> Result<Directory, PatchConfigError> {
+    read_json(&previous_meta_dir.join(crate::PATCHLIST_FILE_NAME))
+}
+
+/// Reads the layout of an output. Outputs generated before the layout was
+/// recorded are trees.
+pub fn load_layout(meta_dir: &Path) -> Result<PublishedLayout, PatchConfigError> {
+    let path = meta_dir.join(crate::LAYOUT_FILE_NAME);
+
+    if !path.exists() {
+        return Ok(PublishedLayout::default());
+    }
+
+    read_json(&path)
+}
+
+fn read_json<T>(path: &Path) -> Result<T, PatchConfigError>
+where
+    T: DeserializeOwned,
+{
+    let text = std::fs::read_to_string(path).map_err(|why| {
+        PatchConfigError::ReleaseFailed(format!(
+            "Unable to read metadata {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    serde_json::from_str(&text).map_err(|why| {
+        PatchConfigError::ReleaseFailed(format!(
+            "Metadata {} is invalid: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })
+}
+
+/// The current time in seconds since the Unix epoch
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
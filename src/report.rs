@@ -0,0 +1,468 @@
+use crate::{
+    diff::{self, ChangeSummary},
+    error::PatchConfigError,
+    fsobject::*,
+    options::ArchiveExtensions,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// How many of the largest files a report lists
+const LARGEST_FILES: usize = 20;
+
+/// A summary of a finished generation
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct GenerationReport {
+    /// Id of the generated release
+    pub release: String,
+    /// Files written to the output
+    pub written_files: u64,
+    pub written_bytes: u64,
+    /// Files which weren't written because the content-addressed output
+    /// already held identical data
+    pub deduplicated_files: u64,
+    pub deduplicated_bytes: u64,
+    /// Written files which were hardlinked or reflinked rather than copied
+    pub linked_files: u64,
+    /// The source followed by each overlay layer, see
+    /// [`crate::options::GenerateOptions::layers`]
+    pub layers: Vec<PathBuf>,
+    /// The layer each file of the patch was taken from, keyed by its path
+    /// relative to the patch root
+    pub origins: BTreeMap<String, LayerOrigin>,
+    /// Problems which didn't stop the generation, such as exceeded budgets
+    /// when the budget is soft
+    pub warnings: Vec<String>,
+    /// What the generated patch holds
+    pub contents: ContentSummary,
+    /// Changes since the previous release, when there is one
+    pub changes: Option<ChangeSummary>,
+    /// How long generating the release took, in seconds
+    pub duration_seconds: f64,
+}
+
+/// Totals and breakdowns of a generated patch tree. Sizes are of the files
+/// clients install, not of their compressed variants.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ContentSummary {
+    /// Files clients install, counting each entry of an unpacked archive and
+    /// the HED and DAT of a packed archive
+    pub files: u64,
+    pub directories: u64,
+    pub archives: u64,
+    pub archive_entries: u64,
+    pub bytes: u64,
+    /// The largest files, largest first
+    pub largest_files: Vec<PathSize>,
+    /// Every archive, by its path relative to the patch root
+    pub archive_sizes: Vec<ArchiveSize>,
+    /// Files and bytes by lowercase extension. Files without an extension
+    /// are listed under an empty one.
+    pub extensions: BTreeMap<String, ExtensionTotal>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PathSize {
+    pub path: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ArchiveSize {
+    pub path: String,
+    pub entries: u64,
+    /// Size of the entries of an unpacked archive, or of the HED and DAT of a
+    /// packed one
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ExtensionTotal {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl ContentSummary {
+    pub fn of(root: &Directory, extensions: &ArchiveExtensions) -> Self {
+        let mut summary = ContentSummary::default();
+        summarize_directory(root, "", extensions, &mut summary);
+
+        let mut files = diff::flatten_files(root, extensions)
+            .into_iter()
+            .map(|(path, file)| PathSize {
+                path,
+                bytes: file.size,
+            })
+            .collect::<Vec<PathSize>>();
+
+        for file in &files {
+            let name = file.path.rsplit('/').next().unwrap_or_default();
+            let extension = match name.rsplit_once('.') {
+                Some((stem, extension)) if !stem.is_empty() => extension.to_lowercase(),
+                _ => String::new(),
+            };
+
+            let total = summary.extensions.entry(extension).or_default();
+            total.files += 1;
+            total.bytes += file.bytes;
+        }
+
+        summary.files = files.len() as u64;
+        summary.bytes = files.iter().map(|file| file.bytes).sum();
+
+        files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+        files.truncate(LARGEST_FILES);
+        summary.largest_files = files;
+
+        summary
+    }
+}
+
+fn summarize_directory(
+    dir: &Directory,
+    dir_path: &str,
+    extensions: &ArchiveExtensions,
+    summary: &mut ContentSummary,
+) {
+    for child in &dir.children {
+        match child {
+            FSObject::File(_) => {}
+            FSObject::Directory(child_dir) => {
+                summary.directories += 1;
+                let child_path = diff::join(dir_path, &child_dir.name);
+                summarize_directory(child_dir, &child_path, extensions, summary);
+            }
+            FSObject::Archive(archive) => {
+                summary.archives += 1;
+                summary.archive_entries += archive.files.len() as u64;
+                summary.archive_sizes.push(ArchiveSize {
+                    path: diff::join(
+                        dir_path,
+                        &format!("{}.{}", archive.name, extensions.unpacked_output()),
+                    ),
+                    entries: archive.files.len() as u64,
+                    bytes: archive.files.iter().map(|file| file.size).sum(),
+                });
+            }
+            FSObject::PackedArchive(archive) => {
+                summary.archives += 1;
+                summary.archive_entries += archive.files.len() as u64;
+                summary.archive_sizes.push(ArchiveSize {
+                    path: diff::join(dir_path, &archive.header.name),
+                    entries: archive.files.len() as u64,
+                    bytes: archive.header.size + archive.data.size,
+                });
+            }
+        }
+    }
+}
+
+impl GenerationReport {
+    pub fn to_json(&self) -> Result<String, PatchConfigError> {
+        serde_json::to_string_pretty(self).map_err(|why| {
+            PatchConfigError::MetadataFailed(format!("Failed to serialize report: {why}"))
+        })
+    }
+
+    /// Renders the report as Markdown, such as for release notes
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = String::new();
+
+        for block in self.blocks() {
+            match block {
+                Block::Heading(level, text) => {
+                    markdown.push_str(&format!("{} {text}\n\n", "#".repeat(level)));
+                }
+                Block::List(items) => {
+                    for item in items {
+                        let (first, rest) = split_item(&item);
+                        markdown.push_str(&format!("- {}\n", escape_markdown(first)));
+                        for line in rest {
+                            markdown.push_str(&format!("  - {}\n", escape_markdown(line)));
+                        }
+                    }
+                    markdown.push('\n');
+                }
+                Block::Table(headers, rows) => {
+                    markdown.push_str(&format!("| {} |\n", headers.join(" | ")));
+                    markdown.push_str(&format!("|{}\n", "---|".repeat(headers.len())));
+                    for row in rows {
+                        let cells = row
+                            .iter()
+                            .map(|cell| escape_markdown(cell))
+                            .collect::<Vec<String>>();
+                        markdown.push_str(&format!("| {} |\n", cells.join(" | ")));
+                    }
+                    markdown.push('\n');
+                }
+            }
+        }
+
+        markdown
+    }
+
+    /// Renders the report as a standalone HTML page
+    pub fn to_html(&self) -> String {
+        let mut body = String::new();
+
+        for block in self.blocks() {
+            match block {
+                Block::Heading(level, text) => {
+                    body.push_str(&format!("<h{level}>{}</h{level}>\n", escape_html(&text)));
+                }
+                Block::List(items) => {
+                    body.push_str("<ul>\n");
+                    for item in items {
+                        let (first, rest) = split_item(&item);
+                        body.push_str(&format!("<li>{}", escape_html(first)));
+                        if !rest.is_empty() {
+                            body.push_str("\n<ul>\n");
+                            for line in rest {
+                                body.push_str(&format!("<li>{}</li>\n", escape_html(line)));
+                            }
+                            body.push_str("</ul>\n");
+                        }
+                        body.push_str("</li>\n");
+                    }
+                    body.push_str("</ul>\n");
+                }
+                Block::Table(headers, rows) => {
+                    body.push_str("<table>\n<tr>");
+                    for header in headers {
+                        body.push_str(&format!("<th>{}</th>", escape_html(header)));
+                    }
+                    body.push_str("</tr>\n");
+                    for row in rows {
+                        body.push_str("<tr>");
+                        for cell in row {
+                            body.push_str(&format!("<td>{}</td>", escape_html(&cell)));
+                        }
+                        body.push_str("</tr>\n");
+                    }
+                    body.push_str("</table>\n");
+                }
+            }
+        }
+
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Release {}</title>\n\
+             <style>body {{ font-family: sans-serif; }} \
+             table {{ border-collapse: collapse; }} \
+             th, td {{ border: 1px solid #ccc; padding: 2px 8px; text-align: left; }}</style>\n\
+             </head>\n<body>\n{body}</body>\n</html>\n",
+            escape_html(&self.release)
+        )
+    }
+
+    /// Lays out the report as a document which Markdown and HTML are
+    /// rendered from
+    fn blocks(&self) -> Vec<Block> {
+        let contents = &self.contents;
+        let mut blocks = vec![Block::Heading(1, format!("Release {}", self.release))];
+
+        let mut summary = vec![
+            vec!["Files".to_string(), contents.files.to_string()],
+            vec!["Directories".to_string(), contents.directories.to_string()],
+            vec![
+                "Archives".to_string(),
+                format!(
+                    "{} ({} entries)",
+                    contents.archives, contents.archive_entries
+                ),
+            ],
+            vec!["Total size".to_string(), format_bytes(contents.bytes)],
+            vec![
+                "Written".to_string(),
+                format!(
+                    "{} files ({})",
+                    self.written_files,
+                    format_bytes(self.written_bytes)
+                ),
+            ],
+        ];
+        if self.deduplicated_files > 0 {
+            summary.push(vec![
+                "Deduplicated".to_string(),
+                format!(
+                    "{} files ({})",
+                    self.deduplicated_files,
+                    format_bytes(self.deduplicated_bytes)
+                ),
+            ]);
+        }
+        summary.push(vec![
+            "Duration".to_string(),
+            format!("{:.1} s", self.duration_seconds),
+        ]);
+        blocks.push(Block::Table(&["Summary", ""], summary));
+
+        if !self.warnings.is_empty() {
+            blocks.push(Block::Heading(2, "Warnings".to_string()));
+            blocks.push(Block::List(self.warnings.clone()));
+        }
+
+        if let Some(changes) = &self.changes {
+            blocks.push(Block::Heading(2, "Changes".to_string()));
+            if changes.is_empty() {
+                blocks.push(Block::List(vec!["No files changed".to_string()]));
+            }
+            for (name, paths) in [
+                ("Added", &changes.added),
+                ("Modified", &changes.modified),
+                ("Removed", &changes.removed),
+            ] {
+                if !paths.is_empty() {
+                    blocks.push(Block::Heading(3, format!("{name} ({})", paths.len())));
+                    blocks.push(Block::List(paths.clone()));
+                }
+            }
+        }
+
+        if !contents.largest_files.is_empty() {
+            blocks.push(Block::Heading(2, "Largest files".to_string()));
+            blocks.push(Block::Table(
+                &["Path", "Size"],
+                contents
+                    .largest_files
+                    .iter()
+                    .map(|file| vec![file.path.clone(), format_bytes(file.bytes)])
+                    .collect(),
+            ));
+        }
+
+        if !contents.archive_sizes.is_empty() {
+            blocks.push(Block::Heading(2, "Archives".to_string()));
+            blocks.push(Block::Table(
+                &["Archive", "Entries", "Size"],
+                contents
+                    .archive_sizes
+                    .iter()
+                    .map(|archive| {
+                        vec![
+                            archive.path.clone(),
+                            archive.entries.to_string(),
+                            format_bytes(archive.bytes),
+                        ]
+                    })
+                    .collect(),
+            ));
+        }
+
+        if !contents.extensions.is_empty() {
+            blocks.push(Block::Heading(2, "By extension".to_string()));
+            blocks.push(Block::Table(
+                &["Extension", "Files", "Size"],
+                contents
+                    .extensions
+                    .iter()
+                    .map(|(extension, total)| {
+                        let extension = match extension.is_empty() {
+                            true => "(none)".to_string(),
+                            false => format!(".{extension}"),
+                        };
+                        vec![
+                            extension,
+                            total.files.to_string(),
+                            format_bytes(total.bytes),
+                        ]
+                    })
+                    .collect(),
+            ));
+        }
+
+        blocks
+    }
+}
+
+/// A piece of a rendered report
+enum Block {
+    Heading(usize, String),
+    List(Vec<String>),
+    Table(&'static [&'static str], Vec<Vec<String>>),
+}
+
+/// Formats a size for people, such as 1.5 MiB
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+
+    format!("{size:.1} {unit}")
+}
+
+/// Splits a list item which spans several lines, such as a warning listing
+/// problems, into its first line and the lines below it, which are shown as
+/// nested items. Lines which are already bulleted lose their bullet.
+fn split_item(item: &str) -> (&str, Vec<&str>) {
+    let mut lines = item.lines().filter(|line| !line.trim().is_empty());
+    let first = lines.next().unwrap_or_default();
+    let rest = lines
+        .map(|line| {
+            let line = line.trim();
+            line.strip_prefix("- ").unwrap_or(line)
+        })
+        .collect();
+
+    (first, rest)
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '[' | ']' | '|' | '<' | '>' | '#'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Which layer a file of the patch was taken from. Layers are indices into
+/// [`GenerationReport::layers`].
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct LayerOrigin {
+    /// The layer whose copy of the file was used
+    pub layer: usize,
+    /// Earlier layers which also provided the file
+    pub overridden: Vec<usize>,
+}
+
+impl LayerOrigin {
+    pub fn new(layer: usize) -> Self {
+        Self {
+            layer,
+            overridden: Vec::new(),
+        }
+    }
+
+    /// Records that a later layer provides the file as well
+    pub fn override_with(&mut self, layer: usize) {
+        if layer != self.layer {
+            self.overridden.push(self.layer);
+            self.layer = layer;
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{error::PatchConfigError, options::ParallelismOptions};
+use rayon::prelude::*;
+use std::sync::{Condvar, Mutex};
+
+/// Decides how the work of a generation is spread over threads, and limits
+/// how much of it touches the disk at once
+pub struct Scheduler {
+    /// A dedicated pool, when the thread count is limited. Otherwise rayon's
+    /// global pool is used.
+    pool: Option<rayon::ThreadPool>,
+    sequential: bool,
+    largest_first: bool,
+    reads: Option<Semaphore>,
+    writes: Option<Semaphore>,
+}
+
+impl Scheduler {
+    pub fn new(options: &ParallelismOptions) -> Result<Self, PatchConfigError> {
+        let pool = match options.threads {
+            Some(threads) if !options.sequential => Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|why| {
+                        PatchConfigError::ThreadPoolFailed(format!(
+                            "Unable to start {threads} threads: {why}"
+                        ))
+                    })?,
+            ),
+            _ => None,
+        };
+
+        Ok(Self {
+            pool,
+            sequential: options.sequential,
+            largest_first: options.largest_first,
+            reads: options.max_concurrent_reads.map(Semaphore::new),
+            writes: options.max_concurrent_writes.map(Semaphore::new),
+        })
+    }
+
+    /// Runs an operation, and everything it spreads over threads, in the
+    /// scheduler's pool
+    pub fn install<R, F>(&self, op: F) -> R
+    where
+        R: Send,
+        F: FnOnce() -> R + Send,
+    {
+        match &self.pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
+    /// Applies a function to every item, keeping the results in order
+    pub fn map<T, R, F>(&self, items: Vec<T>, f: F) -> Vec<R>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(T) -> R + Send + Sync,
+    {
+        if self.sequential {
+            items.into_iter().map(f).collect()
+        } else {
+            items.into_par_iter().map(f).collect()
+        }
+    }
+
+    /// Like [`Scheduler::map`], but when largest-first scheduling is on, items
+    /// are started in order of their estimated size, so a large file isn't
+    /// left to finish alone at the end. Only the given items are ordered, such
+    /// as the entries of one directory.
+    pub fn map_largest_first<T, R, S, F>(&self, items: Vec<T>, size: S, f: F) -> Vec<R>
+    where
+        T: Send,
+        R: Send,
+        S: Fn(&T) -> u64,
+        F: Fn(T) -> R + Send + Sync,
+    {
+        if self.sequential || !self.largest_first {
+            return self.map(items, f);
+        }
+
+        let mut items = items
+            .into_iter()
+            .enumerate()
+            .map(|(index, item)| (size(&item), index, item))
+            .collect::<Vec<(u64, usize, T)>>();
+        items.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        // Bridging hands items to threads in order as they become free
+        let mut results = items
+            .into_iter()
+            .par_bridge()
+            .map(|(_, index, item)| (index, f(item)))
+            .collect::<Vec<(usize, R)>>();
+        results.sort_by_key(|(index, _)| *index);
+
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Runs an operation which reads source data, once the read limit allows
+    pub fn read<R>(&self, op: impl FnOnce() -> R) -> R {
+        let _permit = self.reads.as_ref().map(Semaphore::acquire);
+        op()
+    }
+
+    /// Runs an operation which writes output data, once the write limit
+    /// allows
+    pub fn write<R>(&self, op: impl FnOnce() -> R) -> R {
+        let _permit = self.writes.as_ref().map(Semaphore::acquire);
+        op()
+    }
+}
+
+/// Limits how many threads may do something at once
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self {
+            // A limit of 0 would block forever
+            available: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        let mut available = self
+            .available
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        while *available == 0 {
+            available = self
+                .released
+                .wait(available)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+
+        *available -= 1;
+        Permit { semaphore: self }
+    }
+}
+
+/// Gives its permit back when dropped
+struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut available = self
+            .semaphore
+            .available
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *available += 1;
+        self.semaphore.released.notify_one();
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{error::PatchConfigError, sink::Sink};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Extension appended to the name of a file to get the name of its signature
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Creates a new Ed25519 key pair and writes each key as hex text. The secret
+/// key must stay with whoever generates patches, while the public key is
+/// distributed with launchers.
+pub fn generate_key_files<P>(secret_key_path: P, public_key_path: P) -> Result<(), PatchConfigError>
+where
+    P: AsRef<Path>,
+{
+    let mut secret = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+    getrandom::getrandom(&mut secret).map_err(|why| {
+        PatchConfigError::KeyFailed(format!("Couldn't gather randomness for a new key: {why}"))
+    })?;
+
+    let signing_key = SigningKey::from_bytes(&secret);
+    let secret_key_path = secret_key_path.as_ref();
+    let public_key_path = public_key_path.as_ref();
+
+    // Never replace a key which already exists, nor leave half a pair behind
+    // when only one of them does
+    for path in [secret_key_path, public_key_path] {
+        if path.exists() {
+            return Err(PatchConfigError::KeyFailed(format!(
+                "Key file already exists: {}",
+                path.to_string_lossy()
+            )));
+        }
+    }
+
+    write_key_file(secret_key_path, &hex::encode(signing_key.to_bytes()), true)?;
+    write_key_file(
+        public_key_path,
+        &hex::encode(signing_key.verifying_key().to_bytes()),
+        false,
+    )
+}
+
+/// Creates a key file, failing if one was created in the meantime. Secret
+/// keys are only readable by their owner.
+fn write_key_file(path: &Path, key: &str, secret: bool) -> Result<(), PatchConfigError> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(key.as_bytes()))
+        .map_err(|why| {
+            PatchConfigError::KeyFailed(format!(
+                "Unable to write key file {}: {}",
+                path.to_string_lossy(),
+                why
+            ))
+        })
+}
+
+/// Reads a secret key which was written by generate_key_files
+pub fn load_signing_key<P>(secret_key_path: P) -> Result<SigningKey, PatchConfigError>
+where
+    P: AsRef<Path>,
+{
+    let bytes = read_hex_file::<{ ed25519_dalek::SECRET_KEY_LENGTH }>(secret_key_path.as_ref())?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Reads a public key which was written by generate_key_files
+pub fn load_public_key<P>(public_key_path: P) -> Result<[u8; 32], PatchConfigError>
+where
+    P: AsRef<Path>,
+{
+    read_hex_file::<{ ed25519_dalek::PUBLIC_KEY_LENGTH }>(public_key_path.as_ref())
+}
+
+fn read_hex_file<const N: usize>(path: &Path) -> Result<[u8; N], PatchConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|why| {
+        PatchConfigError::KeyFailed(format!(
+            "Unable to read key file {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    let mut bytes = [0u8; N];
+    hex::decode_to_slice(text.trim(), &mut bytes).map_err(|why| {
+        PatchConfigError::KeyFailed(format!(
+            "Key file {} is not a valid key: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    Ok(bytes)
+}
+
+/// The path of the detached signature of a file
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(".");
+    signature_path.push(SIGNATURE_EXTENSION);
+    PathBuf::from(signature_path)
+}
+
+/// Signs data, returning the signature as hex text
+pub fn sign(signing_key: &SigningKey, data: &[u8]) -> String {
+    hex::encode(signing_key.sign(data).to_bytes())
+}
+
+/// Writes the detached signature of some data, which was written to the given
+/// path, to the path with .sig appended
+pub fn write_signature(
+    sink: &dyn Sink,
+    signing_key: &SigningKey,
+    path: &Path,
+    data: &[u8],
+) -> Result<(), PatchConfigError> {
+    let signature_path = signature_path(path);
+
+    sink.write(&signature_path, sign(signing_key, data).as_bytes())
+        .map_err(|why| {
+            PatchConfigError::WriteMetadataFailed(format!(
+                "Unable to write signature {}: {}",
+                signature_path.to_string_lossy(),
+                why
+            ))
+        })
+}
+
+/// Checks a hex signature of some data against a public key. This is what
+/// launchers should call on patchlist.json and status.json before trusting
+/// them.
+pub fn verify(data: &[u8], signature: &str, public_key: &[u8; 32]) -> Result<(), PatchConfigError> {
+    let public_key = VerifyingKey::from_bytes(public_key)
+        .map_err(|why| PatchConfigError::KeyFailed(format!("Invalid public key: {why}")))?;
+
+    let mut signature_bytes = [0u8; ed25519_dalek::SIGNATURE_LENGTH];
+    hex::decode_to_slice(signature.trim(), &mut signature_bytes).map_err(|why| {
+        PatchConfigError::SignatureInvalid(format!("Signature is not valid hex: {why}"))
+    })?;
+
+    public_key
+        .verify_strict(data, &Signature::from_bytes(&signature_bytes))
+        .map_err(|why| {
+            PatchConfigError::SignatureInvalid(format!("Signature does not match: {why}"))
+        })
+}
+
+/// Checks a file against its detached signature
+pub fn verify_file<P>(path: P, public_key: &[u8; 32]) -> Result<(), PatchConfigError>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let signature_path = signature_path(path);
+
+    let data = std::fs::read(path).map_err(|why| {
+        PatchConfigError::ReadSourceFileFailed(format!(
+            "Failed to read file {}: {}",
+            path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    let signature = std::fs::read_to_string(&signature_path).map_err(|why| {
+        PatchConfigError::SignatureInvalid(format!(
+            "Failed to read signature {}: {}",
+            signature_path.to_string_lossy(),
+            why
+        ))
+    })?;
+
+    verify(&data, &signature, public_key).map_err(|why| {
+        PatchConfigError::SignatureInvalid(format!(
+            "{}: {}",
+            path.to_string_lossy(),
+            why.to_string()
+        ))
+    })
+}
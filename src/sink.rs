@@ -0,0 +1,293 @@
+use crate::options::LinkMode;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Where the output of a generation is written. Paths are given in full, as
+/// they appear under the target directory.
+pub trait Sink: Send + Sync {
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Creates a directory, failing if it already exists
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a directory and any missing parents, unless it already exists
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Writes a file, replacing it if it already exists
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+
+    /// Moves a file, replacing any file at the new path
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes a directory along with everything in it
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Reads back a file which is already in the output
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Names of the files and directories in a directory
+    fn list(&self, path: &Path) -> io::Result<Vec<String>>;
+
+    /// Places a file from disk in the output without copying it through
+    /// memory, if the sink is able to. Otherwise it is read and written.
+    /// Returns if the file shares its storage with the source rather than
+    /// being a copy.
+    fn link(&self, source_path: &Path, target_path: &Path, _mode: LinkMode) -> io::Result<bool> {
+        self.write(target_path, &std::fs::read(source_path)?)?;
+        Ok(false)
+    }
+}
+
+/// Writes the output to disk
+pub struct DiskSink;
+
+impl Sink for DiskSink {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        std::fs::write(path, data)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<String>> {
+        let mut names = Vec::<String>::new();
+
+        for entry in std::fs::read_dir(path)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn link(&self, source_path: &Path, target_path: &Path, mode: LinkMode) -> io::Result<bool> {
+        match mode {
+            LinkMode::Hardlink => std::fs::hard_link(source_path, target_path).map(|_| true),
+            // A size is only returned when the file system can't reflink and
+            // the file was copied instead
+            LinkMode::ReflinkOrCopy => reflink_copy::reflink_or_copy(source_path, target_path)
+                .map(|copied| copied.is_none()),
+            LinkMode::Copy => std::fs::copy(source_path, target_path).map(|_| false),
+        }
+    }
+}
+
+/// Keeps the output in memory, so that it can be inspected without touching
+/// the disk, such as by tests
+#[derive(Default)]
+pub struct MemorySink {
+    contents: Mutex<MemoryContents>,
+}
+
+#[derive(Default)]
+struct MemoryContents {
+    directories: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every file written so far, by path
+    pub fn files(&self) -> BTreeMap<PathBuf, Vec<u8>> {
+        match self.contents.lock() {
+            Ok(contents) => contents.files.clone(),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+
+    /// Like on disk, something can only be created in a directory which
+    /// exists. Relative paths without a parent are in the current directory.
+    fn check_parent(contents: &MemoryContents, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent)
+                if !parent.as_os_str().is_empty() && !contents.directories.contains(parent) =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} isn't in the output", parent.to_string_lossy()),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn contents(&self) -> io::Result<std::sync::MutexGuard<'_, MemoryContents>> {
+        self.contents
+            .lock()
+            .map_err(|_| io::Error::other("The output was poisoned"))
+    }
+}
+
+impl Sink for MemorySink {
+    fn exists(&self, path: &Path) -> bool {
+        self.contents().is_ok_and(|contents| {
+            contents.directories.contains(path) || contents.files.contains_key(path)
+        })
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let mut contents = self.contents()?;
+        Self::check_parent(&contents, path)?;
+
+        if contents.files.contains_key(path) || !contents.directories.insert(path.to_path_buf()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.to_string_lossy()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut contents = self.contents()?;
+
+        for ancestor in path.ancestors() {
+            if contents.files.contains_key(ancestor) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is a file", ancestor.to_string_lossy()),
+                ));
+            }
+        }
+
+        for ancestor in path.ancestors() {
+            if !ancestor.as_os_str().is_empty() {
+                contents.directories.insert(ancestor.to_path_buf());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut contents = self.contents()?;
+        Self::check_parent(&contents, path)?;
+
+        if contents.directories.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is a directory", path.to_string_lossy()),
+            ));
+        }
+
+        contents.files.insert(path.to_path_buf(), data.to_vec());
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut contents = self.contents()?;
+        Self::check_parent(&contents, to)?;
+
+        if contents.directories.contains(to) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is a directory", to.to_string_lossy()),
+            ));
+        }
+
+        let data = contents.files.remove(from).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in the output", from.to_string_lossy()),
+            )
+        })?;
+
+        contents.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.contents()?.files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in the output", path.to_string_lossy()),
+            )),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut contents = self.contents()?;
+
+        if !contents.directories.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in the output", path.to_string_lossy()),
+            ));
+        }
+
+        contents
+            .directories
+            .retain(|directory| !directory.starts_with(path));
+        contents.files.retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.contents()?.files.get(path).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in the output", path.to_string_lossy()),
+            )
+        })
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<String>> {
+        let contents = self.contents()?;
+
+        if !contents.directories.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in the output", path.to_string_lossy()),
+            ));
+        }
+
+        let names = contents
+            .directories
+            .iter()
+            .chain(contents.files.keys())
+            .filter(|child| child.parent() == Some(path))
+            .filter_map(|child| child.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect();
+
+        Ok(names)
+    }
+}
@@ -0,0 +1,755 @@
+use crate::error::PatchConfigError;
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::Metadata,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tempfile::{NamedTempFile, TempPath};
+
+/// Where the files of a generation are read from. Paths are given in full,
+/// as they appear under the source root or layer being read.
+pub trait Source: Send + Sync {
+    /// The files and directories in a directory, in no particular order.
+    /// Anything which is neither is left out.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>>;
+
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata>;
+
+    /// Reads the whole of a file
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// A file on disk holding the same data, for libraries which can only
+    /// open paths
+    fn local_file(&self, path: &Path) -> io::Result<LocalFile>;
+
+    /// If the file exists on disk at its path, so it may be linked into the
+    /// output rather than copied through memory
+    fn is_on_disk(&self, path: &Path) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceEntry {
+    pub path: PathBuf,
+    pub kind: SourceKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceMetadata {
+    pub kind: SourceKind,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub executable: bool,
+    /// Identifies the file on its filesystem, if the source has such a
+    /// thing. Only files with an inode are trusted to the hash cache.
+    pub inode: Option<u64>,
+}
+
+impl SourceMetadata {
+    pub fn is_file(&self) -> bool {
+        self.kind == SourceKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == SourceKind::Directory
+    }
+}
+
+/// A file of the source, along with the metadata it had when the generation
+/// was planned
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub metadata: SourceMetadata,
+}
+
+/// A source file on disk, which is removed when dropped if it is a temporary
+/// copy
+pub struct LocalFile {
+    path: PathBuf,
+    _temporary: Option<TempPath>,
+}
+
+impl LocalFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies data to a temporary file
+    fn temporary(data: &[u8]) -> io::Result<Self> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(data)?;
+        let temporary = file.into_temp_path();
+
+        Ok(Self {
+            path: temporary.to_path_buf(),
+            _temporary: Some(temporary),
+        })
+    }
+}
+
+/// Files and directories on disk
+pub struct DiskSource;
+
+impl Source for DiskSource {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let mut entries = Vec::<SourceEntry>::new();
+
+        for entry in std::fs::read_dir(path)? {
+            let path = entry?.path();
+
+            // Links are followed, and broken links are left out
+            let kind = match std::fs::metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => SourceKind::Directory,
+                Ok(metadata) if metadata.is_file() => SourceKind::File,
+                _ => continue,
+            };
+
+            entries.push(SourceEntry { path, kind });
+        }
+
+        Ok(entries)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        let metadata = std::fs::metadata(path)?;
+
+        Ok(SourceMetadata {
+            kind: if metadata.is_dir() {
+                SourceKind::Directory
+            } else {
+                SourceKind::File
+            },
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            executable: is_executable(&metadata),
+            inode: Some(inode(&metadata)),
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn local_file(&self, path: &Path) -> io::Result<LocalFile> {
+        Ok(LocalFile {
+            path: path.to_path_buf(),
+            _temporary: None,
+        })
+    }
+
+    fn is_on_disk(&self, _path: &Path) -> bool {
+        true
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+/// Other platforms don't record whether a file is executable
+#[cfg(not(unix))]
+fn is_executable(_metadata: &Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+/// Windows only exposes file indices on nightly, so the other parts of a
+/// file's fingerprint have to do
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Extensions of the packaged builds which may be read as sources, such as
+/// the artifacts of a build pipeline
+const PACKAGE_EXTENSIONS: [&str; 4] = [".zip", ".tar", ".tar.zst", ".tzst"];
+
+/// Finds the packaged build a source path refers to, which is either the
+/// package itself or a directory inside it, such as `build.zip/ECO`
+pub fn package_path(path: &Path) -> Option<&Path> {
+    path.ancestors()
+        .find(|ancestor| ancestor.file_name().is_some_and(is_package_name) && ancestor.is_file())
+}
+
+/// A packaged build, which is read without being extracted. Zip entries are
+/// decompressed as they are read. A tar is read in place, except that a
+/// compressed tar is decompressed to a temporary file first, since it can't
+/// be read out of order.
+pub struct PackageSource {
+    /// Path of the package, under which its entries appear
+    root: PathBuf,
+    index: EntryIndex,
+    storage: PackageStorage,
+}
+
+enum PackageStorage {
+    Zip {
+        path: PathBuf,
+        /// Opened archives which no read is using. Each read takes one, or
+        /// opens another, so reads on different threads don't wait for each
+        /// other.
+        idle: Mutex<Vec<zip::ZipArchive<std::fs::File>>>,
+    },
+    Tar {
+        path: PathBuf,
+        /// The decompressed copy of a compressed tar, which `path` points to
+        _decompressed: Option<TempPath>,
+    },
+}
+
+impl PackageSource {
+    /// Reads the index of a packaged build
+    pub fn open(root: &Path) -> io::Result<Self> {
+        let mut index = EntryIndex::new(root);
+
+        let name = root
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().to_lowercase());
+
+        let storage = if name.ends_with(".zip") {
+            let mut archive = zip::ZipArchive::new(std::fs::File::open(root)?)?;
+            index.add_zip(&mut archive)?;
+            PackageStorage::Zip {
+                path: root.to_path_buf(),
+                idle: Mutex::new(vec![archive]),
+            }
+        } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+            let mut decompressed = NamedTempFile::new()?;
+            zstd::stream::copy_decode(std::fs::File::open(root)?, &mut decompressed)?;
+            let decompressed = decompressed.into_temp_path();
+
+            index.add_tar(&decompressed)?;
+            PackageStorage::Tar {
+                path: decompressed.to_path_buf(),
+                _decompressed: Some(decompressed),
+            }
+        } else {
+            index.add_tar(root)?;
+            PackageStorage::Tar {
+                path: root.to_path_buf(),
+                _decompressed: None,
+            }
+        };
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            index,
+            storage,
+        })
+    }
+
+    /// The entry at a path under the package's root
+    fn entry(&self, path: &Path) -> io::Result<&IndexedEntry> {
+        path.strip_prefix(&self.root)
+            .ok()
+            .and_then(|inner| self.index.entries.get(inner))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} isn't in the package", path.to_string_lossy()),
+                )
+            })
+    }
+}
+
+/// The files and directories of a package or of memory, by their paths
+/// within it
+struct EntryIndex {
+    root: PathBuf,
+    entries: HashMap<PathBuf, IndexedEntry>,
+    children: HashMap<PathBuf, Vec<SourceEntry>>,
+}
+
+struct IndexedEntry {
+    metadata: SourceMetadata,
+    location: EntryLocation,
+}
+
+enum EntryLocation {
+    Directory,
+    /// Index of the entry in a zip
+    Zip(usize),
+    /// Offset of the entry's data in a tar
+    Tar(u64),
+    Memory(Vec<u8>),
+}
+
+impl EntryIndex {
+    fn new(root: &Path) -> Self {
+        let mut index = Self {
+            root: root.to_path_buf(),
+            entries: HashMap::new(),
+            children: HashMap::new(),
+        };
+        index.insert_directory(PathBuf::new());
+        index
+    }
+
+    fn add_zip(&mut self, archive: &mut zip::ZipArchive<std::fs::File>) -> io::Result<()> {
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+
+            // Entries which would escape the package are never read
+            let path = match entry.enclosed_name() {
+                Some(x) => x.to_path_buf(),
+                None => continue,
+            };
+
+            if entry.is_dir() {
+                self.insert_directory(path);
+                continue;
+            }
+
+            let metadata = SourceMetadata {
+                kind: SourceKind::File,
+                len: entry.size(),
+                modified: zip_time(entry.last_modified()),
+                executable: entry.unix_mode().is_some_and(|mode| mode & 0o111 != 0),
+                inode: None,
+            };
+            self.insert_file(path, metadata, EntryLocation::Zip(index));
+        }
+
+        Ok(())
+    }
+
+    fn add_tar(&mut self, tar_path: &Path) -> io::Result<()> {
+        let mut archive = tar::Archive::new(std::fs::File::open(tar_path)?);
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+
+            let path = match normalize(&entry.path()?) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            // Links and special files are left out
+            match header.entry_type() {
+                tar::EntryType::Directory => self.insert_directory(path),
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let metadata = SourceMetadata {
+                        kind: SourceKind::File,
+                        len: entry.size(),
+                        modified: header
+                            .mtime()
+                            .ok()
+                            .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime)),
+                        executable: header.mode().is_ok_and(|mode| mode & 0o111 != 0),
+                        inode: None,
+                    };
+                    let location = EntryLocation::Tar(entry.raw_file_position());
+                    self.insert_file(path, metadata, location);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a directory, along with any parents which the package doesn't
+    /// list on their own
+    fn insert_directory(&mut self, path: PathBuf) {
+        if self.entries.contains_key(&path) {
+            return;
+        }
+
+        self.entries.insert(
+            path.clone(),
+            IndexedEntry {
+                metadata: SourceMetadata {
+                    kind: SourceKind::Directory,
+                    len: 0,
+                    modified: None,
+                    executable: false,
+                    inode: None,
+                },
+                location: EntryLocation::Directory,
+            },
+        );
+        self.add_child(&path, SourceKind::Directory);
+    }
+
+    fn insert_file(&mut self, path: PathBuf, metadata: SourceMetadata, location: EntryLocation) {
+        let entry = IndexedEntry { metadata, location };
+
+        match self.entries.get(&path) {
+            // A directory can't be replaced, since its entries are listed
+            Some(existing) if existing.metadata.is_dir() => {}
+            // A later file with the same path replaces an earlier one, like
+            // it would when extracting
+            Some(_) => {
+                self.entries.insert(path, entry);
+            }
+            None => {
+                self.entries.insert(path.clone(), entry);
+                self.add_child(&path, SourceKind::File);
+            }
+        }
+    }
+
+    fn add_child(&mut self, path: &Path, kind: SourceKind) {
+        let parent = match path.parent() {
+            Some(x) => x.to_path_buf(),
+            None => return,
+        };
+
+        self.insert_directory(parent.clone());
+        self.children.entry(parent).or_default().push(SourceEntry {
+            path: self.root.join(path),
+            kind,
+        });
+    }
+}
+
+impl Source for PackageSource {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let entry = self.entry(path)?;
+        if !entry.metadata.is_dir() {
+            return Err(io::Error::other(format!(
+                "{} isn't a directory",
+                path.to_string_lossy()
+            )));
+        }
+
+        let inner = path.strip_prefix(&self.root).unwrap_or(path);
+        Ok(self.index.children.get(inner).cloned().unwrap_or_default())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        Ok(self.entry(path)?.metadata.clone())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let entry = self.entry(path)?;
+        let mut data = Vec::<u8>::with_capacity(entry.metadata.len as usize);
+
+        match (&entry.location, &self.storage) {
+            (EntryLocation::Zip(index), PackageStorage::Zip { path, idle }) => {
+                let idle_archive = idle
+                    .lock()
+                    .map_err(|_| io::Error::other("The package was poisoned"))?
+                    .pop();
+                let mut archive = match idle_archive {
+                    Some(x) => x,
+                    None => zip::ZipArchive::new(std::fs::File::open(path)?)?,
+                };
+
+                archive.by_index(*index)?.read_to_end(&mut data)?;
+
+                if let Ok(mut idle) = idle.lock() {
+                    idle.push(archive);
+                }
+            }
+            // Every read opens the tar again, so reads needn't wait for each
+            // other
+            (EntryLocation::Tar(offset), PackageStorage::Tar { path, .. }) => {
+                let mut file = std::fs::File::open(path)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                file.take(entry.metadata.len).read_to_end(&mut data)?;
+            }
+            _ => {
+                return Err(io::Error::other(format!(
+                    "{} isn't a file",
+                    path.to_string_lossy()
+                )));
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn local_file(&self, path: &Path) -> io::Result<LocalFile> {
+        LocalFile::temporary(&self.read(path)?)
+    }
+
+    fn is_on_disk(&self, _path: &Path) -> bool {
+        false
+    }
+}
+
+/// Files and directories held in memory, such as an ECO tree built by a
+/// test. Their paths are used as they are given, so the source root passed
+/// to the generator should be a directory added here.
+pub struct MemorySource {
+    index: EntryIndex,
+}
+
+impl Default for MemorySource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self {
+            index: EntryIndex::new(Path::new("")),
+        }
+    }
+
+    /// Adds an empty directory, along with any directories above it
+    pub fn add_dir<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.index.insert_directory(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a file, along with any directories above it. A file which was
+    /// already added with the same path is replaced.
+    pub fn add_file<P, D>(&mut self, path: P, data: D) -> &mut Self
+    where
+        P: AsRef<Path>,
+        D: Into<Vec<u8>>,
+    {
+        let data = data.into();
+        let metadata = SourceMetadata {
+            kind: SourceKind::File,
+            len: data.len() as u64,
+            modified: None,
+            executable: false,
+            inode: None,
+        };
+
+        self.index.insert_file(
+            path.as_ref().to_path_buf(),
+            metadata,
+            EntryLocation::Memory(data),
+        );
+        self
+    }
+
+    fn entry(&self, path: &Path) -> io::Result<&IndexedEntry> {
+        self.index.entries.get(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in memory", path.to_string_lossy()),
+            )
+        })
+    }
+}
+
+impl Source for MemorySource {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        if !self.entry(path)?.metadata.is_dir() {
+            return Err(io::Error::other(format!(
+                "{} isn't a directory",
+                path.to_string_lossy()
+            )));
+        }
+
+        Ok(self.index.children.get(path).cloned().unwrap_or_default())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        Ok(self.entry(path)?.metadata.clone())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match &self.entry(path)?.location {
+            EntryLocation::Memory(data) => Ok(data.clone()),
+            _ => Err(io::Error::other(format!(
+                "{} isn't a file",
+                path.to_string_lossy()
+            ))),
+        }
+    }
+
+    fn local_file(&self, path: &Path) -> io::Result<LocalFile> {
+        LocalFile::temporary(&self.read(path)?)
+    }
+
+    fn is_on_disk(&self, _path: &Path) -> bool {
+        false
+    }
+}
+
+/// The path of a tar entry relative to the package, unless it would escape
+/// the package
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    (!normalized.as_os_str().is_empty()).then_some(normalized)
+}
+
+/// Zip times have no time zone, so they are taken as UTC
+fn zip_time(time: zip::DateTime) -> Option<SystemTime> {
+    let (year, month, day) = (
+        i64::from(time.year()),
+        i64::from(time.month()),
+        i64::from(time.day()),
+    );
+
+    // Days since the epoch of a date in the proleptic Gregorian calendar
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86_400
+        + i64::from(time.hour()) * 3_600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+
+    u64::try_from(seconds)
+        .ok()
+        .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Every source root and layer of a generation, each of which is a directory
+/// on disk or a directory in a packaged build. Paths are read from whichever
+/// they belong to.
+pub struct Sources {
+    disk: DiskSource,
+    packages: Vec<(PathBuf, Box<dyn Source>)>,
+}
+
+impl Sources {
+    /// Opens the sources with the given roots, opening the packaged builds
+    /// any of them are in
+    pub fn open(roots: &[PathBuf]) -> Result<Self, PatchConfigError> {
+        let mut sources = Self {
+            disk: DiskSource,
+            packages: Vec::new(),
+        };
+
+        for root in roots {
+            if root.is_dir() {
+                continue;
+            }
+
+            if let Some(package) = package_path(root) {
+                if !sources.packages.iter().any(|(path, _)| path == package) {
+                    let source = PackageSource::open(package).map_err(|why| {
+                        PatchConfigError::ReadSourceFileFailed(format!(
+                            "Unable to read package {}: {}",
+                            package.to_string_lossy(),
+                            why
+                        ))
+                    })?;
+                    sources
+                        .packages
+                        .push((package.to_path_buf(), Box::new(source)));
+                }
+            }
+        }
+
+        Ok(sources)
+    }
+
+    fn source(&self, path: &Path) -> &dyn Source {
+        self.packages
+            .iter()
+            .find(|(root, _)| path.starts_with(root))
+            .map_or(&self.disk, |(_, source)| source.as_ref())
+    }
+}
+
+impl Source for Sources {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        self.source(path).read_dir(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        self.source(path).metadata(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.source(path).read(path)
+    }
+
+    fn local_file(&self, path: &Path) -> io::Result<LocalFile> {
+        self.source(path).local_file(path)
+    }
+
+    fn is_on_disk(&self, path: &Path) -> bool {
+        self.source(path).is_on_disk(path)
+    }
+}
+
+/// If a file name is that of a packaged build
+fn is_package_name(name: &OsStr) -> bool {
+    let name = name.to_string_lossy().to_lowercase();
+    PACKAGE_EXTENSIONS
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn tar_bytes(path: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_data(&mut header, path, data).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn zip_bytes(path: &str, data: &[u8]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        writer
+            .start_file(path, zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn opens_every_package_extension() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for extension in PACKAGE_EXTENSIONS {
+            let package_path = dir.path().join(format!("build{extension}"));
+            let contents = match extension {
+                ".zip" => zip_bytes("ECO/ECO.exe", b"base"),
+                ".tar" => tar_bytes("ECO/ECO.exe", b"base"),
+                _ => zstd::encode_all(&tar_bytes("ECO/ECO.exe", b"base")[..], 0).unwrap(),
+            };
+            std::fs::write(&package_path, contents).unwrap();
+
+            let package = PackageSource::open(&package_path)
+                .unwrap_or_else(|why| panic!("Couldn't open a {extension} package: {why}"));
+            let data = package
+                .read(&package_path.join("ECO").join("ECO.exe"))
+                .unwrap_or_else(|why| panic!("Couldn't read from a {extension} package: {why}"));
+            assert_eq!(data, b"base", "{extension}");
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{fsobject::*, options::ArchiveExtensions};
+use std::collections::HashMap;
+
+/// Longest path Windows clients can handle without long path support
+const WINDOWS_MAX_PATH: usize = 260;
+
+/// Characters of [`WINDOWS_MAX_PATH`] left for the directory the game is
+/// installed in, such as C:\Program Files (x86)\Game, when no other length is
+/// given
+pub const DEFAULT_WINDOWS_INSTALL_PREFIX: usize = 100;
+
+/// Characters which may not appear anywhere in a Windows file name
+const WINDOWS_ILLEGAL_CHARACTERS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Device names which Windows reserves, with or without an extension
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Which paths Windows clients can install. Paths are checked relative to the
+/// install directory, whose own length is reserved from the path limit.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowsLimits {
+    max_path: usize,
+    install_prefix: usize,
+}
+
+impl WindowsLimits {
+    /// Reserves the given number of characters for the install directory, or
+    /// [`DEFAULT_WINDOWS_INSTALL_PREFIX`]
+    pub fn new(install_prefix: Option<usize>) -> Self {
+        let install_prefix = install_prefix.unwrap_or(DEFAULT_WINDOWS_INSTALL_PREFIX);
+        Self {
+            // The install directory is followed by a separator
+            max_path: WINDOWS_MAX_PATH.saturating_sub(install_prefix + 1),
+            install_prefix,
+        }
+    }
+}
+
+/// Walks a generated patch tree and describes every name which would break
+/// when the patch is installed on a Windows client. An empty result means the
+/// tree is safe to publish.
+pub fn windows_name_problems(
+    root: &Directory,
+    extensions: &ArchiveExtensions,
+    limits: WindowsLimits,
+) -> Vec<String> {
+    let mut problems = Vec::<String>::new();
+    check_directory(root, "", extensions, limits, &mut problems);
+    problems
+}
+
+/// Describes the names of one directory which would break on a Windows
+/// client, before anything is generated for them. Names are given as they will
+/// appear in the output, and the directory path is relative to the patch
+/// root.
+pub fn check_planned_names(
+    dir_path: &str,
+    names: &[String],
+    limits: WindowsLimits,
+    problems: &mut Vec<String>,
+) {
+    let mut seen = HashMap::<String, String>::new();
+
+    for name in names {
+        let path = join_path(dir_path, name);
+        check_name(name, &path, limits, problems);
+        check_collision(&mut seen, name, &path, problems);
+    }
+}
+
+fn check_directory(
+    dir: &Directory,
+    dir_path: &str,
+    extensions: &ArchiveExtensions,
+    limits: WindowsLimits,
+    problems: &mut Vec<String>,
+) {
+    // Names which only differ by case refer to the same file on Windows
+    let mut seen = HashMap::<String, String>::new();
+
+    for child in &dir.children {
+        let name = match child {
+            FSObject::File(file) => file.name.clone(),
+            FSObject::Directory(dir) => dir.name.clone(),
+            FSObject::Archive(archive) => {
+                format!("{}.{}", archive.name, extensions.unpacked_output())
+            }
+            FSObject::PackedArchive(archive) => archive.header.name.clone(),
+        };
+
+        let path = join_path(dir_path, &name);
+        check_name(&name, &path, limits, problems);
+        check_collision(&mut seen, &name, &path, problems);
+
+        match child {
+            FSObject::File(_) => {}
+            FSObject::Directory(dir) => check_directory(dir, &path, extensions, limits, problems),
+            FSObject::Archive(archive) => check_archive(&archive.files, &path, limits, problems),
+            FSObject::PackedArchive(archive) => {
+                // The DAT sits next to the HED
+                let data_path = join_path(dir_path, &archive.data.name);
+                check_name(&archive.data.name, &data_path, limits, problems);
+                check_collision(&mut seen, &archive.data.name, &data_path, problems);
+
+                check_archive(&archive.files, &path, limits, problems);
+            }
+        }
+    }
+}
+
+fn check_archive(
+    files: &[File],
+    archive_path: &str,
+    limits: WindowsLimits,
+    problems: &mut Vec<String>,
+) {
+    let mut seen = HashMap::<String, String>::new();
+
+    for file in files {
+        let path = join_path(archive_path, &file.name);
+        check_name(&file.name, &path, limits, problems);
+        check_collision(&mut seen, &file.name, &path, problems);
+    }
+}
+
+/// Checks a single path component, and the length of the path it ends
+fn check_name(name: &str, path: &str, limits: WindowsLimits, problems: &mut Vec<String>) {
+    if let Some(c) = name
+        .chars()
+        .find(|c| WINDOWS_ILLEGAL_CHARACTERS.contains(c) || c.is_ascii_control())
+    {
+        problems.push(format!("{path}: contains the illegal character {c:?}"));
+    }
+
+    if name.ends_with('.') || name.ends_with(' ') {
+        problems.push(format!("{path}: ends with a dot or a space"));
+    }
+
+    // "aux.txt" is just as reserved as "AUX"
+    let device_name = name.split('.').next().unwrap_or(name).trim_end();
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(device_name))
+    {
+        problems.push(format!("{path}: uses the reserved name {device_name}"));
+    }
+
+    let length = path.encode_utf16().count();
+    if length > limits.max_path {
+        problems.push(format!(
+            "{path}: path is {length} characters long, the limit is {} after reserving {} for the install directory",
+            limits.max_path, limits.install_prefix
+        ));
+    }
+}
+
+fn check_collision(
+    seen: &mut HashMap<String, String>,
+    name: &str,
+    path: &str,
+    problems: &mut Vec<String>,
+) {
+    if let Some(other) = seen.insert(name.to_lowercase(), path.to_string()) {
+        problems.push(format!(
+            "{path}: collides with {other} on case-insensitive filesystems"
+        ));
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}/{name}")
+    }
+}